@@ -14,6 +14,9 @@ struct Cli {
     #[command(subcommand)]
     subcommand: Subcommands,
 
+    #[command(flatten)]
+    download_args: royal_road_archiver_lib::DownloadArgs,
+
     /// Enter the URL of the Webnovel
     book_url: String,
 
@@ -39,46 +42,76 @@ pub enum Subcommands {
     /// Generate a markdown file from the Webnovel.
     /// 'markdown --help' for available arguments.
     Markdown(royal_road_archiver_lib::MarkdownArgs),
+
+    /// Generate a pdf from the Webnovel.
+    /// The pdf fonts only cover western european text, other characters (E.G. CJK, Cyrillic or box drawing) become '?'.
+    /// 'pdf --help' for available arguments.
+    Pdf(royal_road_archiver_lib::PdfArgs),
+
+    /// Generate a plain text file from the Webnovel.
+    /// 'txt --help' for available arguments.
+    Txt(royal_road_archiver_lib::TxtArgs),
 }
 
 fn main() {
     let cli_input = Cli::parse();
 
     // Turn the inputted string into a path, or grab the current directory if empty.
-    let output_directory: PathBuf;
-    match cli_input.output_directory {
-        Some(output_directory_input) => {
-            output_directory = PathBuf::from(&output_directory_input);
-        },
-        None => {
-            output_directory = env::current_dir().unwrap();
-        },
-    }
+    let output_directory: PathBuf = match cli_input.output_directory {
+        Some(output_directory_input) => PathBuf::from(&output_directory_input),
+        None => env::current_dir().unwrap(),
+    };
 
     valid_directory_check(&output_directory);
     let book_url = valid_url_check(&cli_input.book_url.to_lowercase());
 
+    let download_args = cli_input.download_args;
+
     let result: Result<
         std::sync::MutexGuard<'_, royal_road_archiver_lib::GenerationWarnings>,
         GenerationError,
     > = match cli_input.subcommand {
-        Subcommands::Audiobook(audiobook_args) => {
-            royal_road_archiver_lib::generate_audiobook(audiobook_args, book_url, output_directory)
-        },
-        Subcommands::Epub(epub_args) => {
-            royal_road_archiver_lib::generate_epub(epub_args, book_url, output_directory)
-        },
-        Subcommands::Html(html_args) => {
-            royal_road_archiver_lib::generate_html(html_args, book_url, output_directory)
-        },
-        Subcommands::Markdown(markdown_args) => {
-            royal_road_archiver_lib::generate_markdown(markdown_args, book_url, output_directory)
-        },
+        Subcommands::Audiobook(audiobook_args) => royal_road_archiver_lib::generate_audiobook(
+            audiobook_args,
+            download_args,
+            book_url,
+            output_directory,
+        ),
+        Subcommands::Epub(epub_args) => royal_road_archiver_lib::generate_epub(
+            epub_args,
+            download_args,
+            book_url,
+            output_directory,
+        ),
+        Subcommands::Html(html_args) => royal_road_archiver_lib::generate_html(
+            html_args,
+            download_args,
+            book_url,
+            output_directory,
+        ),
+        Subcommands::Markdown(markdown_args) => royal_road_archiver_lib::generate_markdown(
+            markdown_args,
+            download_args,
+            book_url,
+            output_directory,
+        ),
+        Subcommands::Pdf(pdf_args) => royal_road_archiver_lib::generate_pdf(
+            pdf_args,
+            download_args,
+            book_url,
+            output_directory,
+        ),
+        Subcommands::Txt(txt_args) => royal_road_archiver_lib::generate_txt(
+            txt_args,
+            download_args,
+            book_url,
+            output_directory,
+        ),
     };
 
     match result {
         Ok(generation_warnings) => {
-            if !&generation_warnings.warnings_count() == 0 {
+            if generation_warnings.warnings_count() != 0 {
                 println!("The following warnings were generated:");
                 for warning in generation_warnings.get_warnings() {
                     println!("\n{warning}");
@@ -112,7 +145,7 @@ fn valid_url_check(book_url: &str) -> Url {
     match Url::parse(book_url) {
         Ok(book_url) => {
             if book_url.host_str() == Some("www.royalroad.com") {
-                return book_url;
+                book_url
             } else {
                 eprintln!("Error! Please enter a RoyalRoad URL.");
                 exit(1);
@@ -1,11 +1,17 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
-use crate::{misc::HashMapExt, GenerationError};
+use crate::{
+    http::{Fetcher, HttpResponse},
+    misc::{self, HashMapExt},
+    record::{BookRecord, ChapterRecord},
+    AuthorNotes, DownloadArgs, GenerationError, Warning, WARNINGS,
+};
+use chrono::{DateTime, Local, Utc};
 use indicatif::{ProgressBar, ProgressStyle};
 use scraper::Html;
 use url::Url;
 
-use crate::{file_system_crap::remove_illegal_chars, html, http};
+use crate::{file_system_crap::remove_illegal_chars, html};
 
 /// A struct representing a book & all the needed data to generate one.
 pub struct Book {
@@ -25,31 +31,90 @@ pub struct Book {
     /// A Url to the book's cover image.
     pub cover_image_url: Url,
 
-    /// The raw html data of the RoyalRoad index page.
-    index_html: Html,
+    /// The book's description (synopsis) as an html fragment.
+    pub description: Html,
+
+    /// The book's RoyalRoad fiction ID.
+    pub fiction_id: Option<u64>,
+
+    /// The book's genre tags. E.G. Fantasy, Action
+    pub tags: Vec<String>,
+
+    /// The book's content warnings. E.G. Profanity, Gore
+    pub content_warnings: Vec<String>,
+
+    /// The book's status. None if it isn't shown on the index.
+    pub status: Option<FictionStatus>,
+
+    /// The book's statistics, like the page count, followers & ratings.
+    pub stats: FictionStats,
 
     /// A vector of the book's chapters.
     pub chapters: Vec<Chapter>,
 
+    /// The volumes the book is split into, in order. Empty if the book doesn't use volumes.
+    pub volumes: Vec<Volume>,
+
     /// A hashmap representing the book image urls and their corresponding img html tags.
     pub image_urls_and_tags: HashMap<Url, Vec<String>>,
 }
 
 impl Book {
     /// Generate a new book instance with all the needed data from a given url.
-    pub fn new(book_url: Url) -> Result<Book, GenerationError> {
-        let index_html =
-            html::string_to_html_document(&http::get_response(book_url.clone())?.get_text()?);
+    ///
+    /// If a record directory is given the book is being updated. Recorded chapters that are still fresh
+    /// are read from the cache, so only new or changed chapters are downloaded again.
+    /// RoyalRoad doesn't send any validators, so the record is what tells if a chapter has changed.
+    ///
+    /// Otherwise every chapter is downloaded again, unless resuming or reading from a cache directory,
+    /// since the cache may hold pages from an earlier run.
+    ///
+    /// Chapters that fail to download are skipped so the rest can still be downloaded.
+    /// An error is returned at the end if any failed.
+    ///
+    /// Only the chapters picked by the chapter selection in the download args are included,
+    /// and up to ``jobs`` chapters are downloaded at the same time.
+    pub fn new(
+        fetcher: &dyn Fetcher,
+        book_url: Url,
+        record_directory: Option<&Path>,
+        download_args: &DownloadArgs,
+    ) -> Result<Book, GenerationError> {
+        let index_html = html::string_to_html_document(&fetcher.fetch_fresh(&book_url)?.get_text());
+
+        let title = html::get_title_from_index(&index_html, &book_url)?;
+        let file_name_title = remove_illegal_chars(title.clone());
 
-        let chapter_names_and_urls =
-            html::get_chapter_names_and_urls_from_index(&index_html, &book_url)?;
-        let mut chapters: Vec<Chapter> = Vec::with_capacity(chapter_names_and_urls.len());
+        let chapter_metadata = html::get_chapter_metadata_from_index(&index_html, &book_url)?;
 
-        let mut image_urls_and_tags: HashMap<Url, Vec<String>> = HashMap::new();
+        // Only keep the chapters that have been selected.
+        let total_chapters = chapter_metadata.len();
+        let chapter_metadata = download_args.chapter_selection.apply(chapter_metadata);
+        if chapter_metadata.is_empty() {
+            return Err(GenerationError::NoChaptersSelectedError { url: book_url });
+        }
+        if !download_args.chapter_selection.is_empty() {
+            println!(
+                "\nSelected {0} of {total_chapters} chapters.",
+                chapter_metadata.len()
+            );
+        }
+
+        // Grab the chapters recorded by the previous run, keyed by their url.
+        let mut recorded_chapters: HashMap<Url, ChapterRecord> = HashMap::new();
+        if let Some(record_directory) = record_directory {
+            if let Some(record) =
+                BookRecord::read(&BookRecord::path(record_directory, &file_name_title))
+            {
+                for chapter_record in record.chapters {
+                    recorded_chapters.insert(chapter_record.chapter_url.clone(), chapter_record);
+                }
+            }
+        }
 
         println!("\nDownloading and processing chapters:");
         // Spawn a progress bar showing how many chapters have been downloaded & processed.
-        let progress_bar = ProgressBar::new(chapter_names_and_urls.len().try_into().unwrap());
+        let progress_bar = ProgressBar::new(chapter_metadata.len().try_into().unwrap());
         progress_bar.set_style(
             ProgressStyle::with_template(
                 "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent}%  ",
@@ -58,73 +123,444 @@ impl Book {
             .progress_chars("#>-"),
         );
 
-        // Generate the chapters and add em to the book.
-        for i in 0..chapter_names_and_urls.len() {
-            let chapter = Chapter::new(&chapter_names_and_urls[i].0, &chapter_names_and_urls[i].1)?;
+        // A slot for each chapter so they stay in order no matter when they finish downloading.
+        let mut chapter_slots: Vec<Option<Chapter>> = Vec::with_capacity(chapter_metadata.len());
+        chapter_slots.resize_with(chapter_metadata.len(), || None);
 
-            // extract the image urls and add em to the image_urls_and_tags hashmap.
-            image_urls_and_tags = image_urls_and_tags.join(html::extract_urls_and_img_tag(
-                &chapter.isolated_chapter_html,
-            ));
+        let mut failed_chapters: Vec<Url> = Vec::new();
+        let mut unchanged_chapters: usize = 0;
 
-            chapters.push(chapter);
+        // Download the chapters and add em to the book.
+        // Fresh recorded chapters come from the cache, the rest get downloaded again if they might be outdated.
+        let revalidate = record_directory.is_some()
+            || !(download_args.resume || download_args.cache_directory.is_some());
+        let fresh_record = |metadata: &ChapterMetadata| {
+            recorded_chapters
+                .get(&metadata.url)
+                .filter(|chapter_record| chapter_record.is_fresh(metadata))
+        };
+        misc::parallel_for_each(
+            &chapter_metadata,
+            download_args.jobs,
+            |metadata| {
+                let revalidate = revalidate && fresh_record(metadata).is_none();
+                Chapter::download_page(fetcher, &metadata.url, revalidate)
+            },
+            |i, page| {
+                let chapter_url = &chapter_metadata[i].url;
 
-            progress_bar.inc(1);
-        }
+                match page.and_then(|page| Chapter::new(chapter_metadata[i].clone(), page)) {
+                    Ok(mut chapter) => {
+                        if let Some(chapter_record) = fresh_record(&chapter_metadata[i]) {
+                            // Keep when it was actually downloaded, so the next update can still tell if it changed.
+                            chapter.fetched_at = chapter_record.fetched_at;
+                            unchanged_chapters += 1;
+                        } else if recorded_chapters
+                            .get(chapter_url)
+                            .is_some_and(|chapter_record| {
+                                chapter_record.is_unchanged(&chapter.etag, &chapter.last_modified)
+                            })
+                        {
+                            unchanged_chapters += 1;
+                        }
+
+                        chapter_slots[i] = Some(chapter);
+                    },
+                    Err(error) => {
+                        progress_bar.println(format!("Failed to download {chapter_url}: {error}"));
+                        failed_chapters.push(chapter_url.clone());
+                    },
+                }
+
+                progress_bar.inc(1);
+            },
+        );
 
         progress_bar.finish();
 
-        let title = html::get_title_from_index(&index_html, &book_url)?;
+        if !recorded_chapters.is_empty() {
+            println!(
+                "\n{unchanged_chapters} of {0} chapters are unchanged since the last run.",
+                chapter_metadata.len()
+            );
+        }
+
+        if !failed_chapters.is_empty() {
+            return Err(GenerationError::IncompleteDownloadError { failed_chapters });
+        }
+
+        let chapters: Vec<Chapter> = chapter_slots.into_iter().flatten().collect();
+
+        // extract the image urls and add em to the image_urls_and_tags hashmap.
+        // The author's notes can have images too. E.G. stat sheets.
+        let mut image_urls_and_tags: HashMap<Url, Vec<String>> = HashMap::new();
+        for chapter in &chapters {
+            let chapter_html = [
+                chapter.pre_chapter_note.as_ref(),
+                Some(&chapter.isolated_chapter_html),
+                chapter.post_chapter_note.as_ref(),
+            ];
+            for html in chapter_html.into_iter().flatten() {
+                image_urls_and_tags =
+                    image_urls_and_tags.join(html::extract_urls_and_img_tag(html));
+            }
+        }
+
+        // Not every book has a description, so fall back to an empty one instead of failing the whole book.
+        let description = match html::get_description_from_index(&index_html) {
+            Some(description) => description,
+            None => {
+                let warning = Warning::MissingDescription {
+                    warning_msg: "Unable to find the book description, leaving it empty"
+                        .to_string(),
+                    url: book_url.clone(),
+                };
+                WARNINGS.lock().unwrap().add_warning(warning);
+
+                Html::new_fragment()
+            },
+        };
 
         let book = Book {
             author: html::get_author_from_index(&index_html, &book_url)?,
             cover_image_url: html::get_cover_image_url_from_index(&index_html, &book_url)?,
-            book_url: book_url,
-            title: title.clone(),
-            file_name_title: remove_illegal_chars(title),
-            index_html: index_html,
-            chapters: chapters,
-            image_urls_and_tags: image_urls_and_tags,
+            description,
+            fiction_id: html::get_fiction_id_from_index(&index_html, &book_url),
+            tags: html::get_tags_from_index(&index_html),
+            content_warnings: html::get_content_warnings_from_index(&index_html),
+            status: html::get_status_from_index(&index_html),
+            stats: html::get_stats_from_index(&index_html),
+            book_url,
+            title,
+            file_name_title,
+            volumes: html::get_volumes_from_index(&index_html),
+            chapters,
+            image_urls_and_tags,
         };
 
-        return Ok(book);
+        Ok(book)
+    }
+
+    /// Write a record of the book into the given directory so later runs can update it.
+    pub fn write_record(&self, record_directory: &Path) -> Result<(), GenerationError> {
+        let record = BookRecord {
+            book_url: self.book_url.clone(),
+            title: self.title.clone(),
+            chapters: self.chapters.iter().map(Chapter::to_record).collect(),
+        };
+
+        record.write(&BookRecord::path(record_directory, &self.file_name_title))
+    }
+
+    /// Group the chapters by the volume they are in, keeping them in order.
+    ///
+    /// Books without volumes end up as a single group.
+    pub fn chapters_by_volume(&self) -> Vec<VolumeGroup<'_>> {
+        let mut groups: Vec<VolumeGroup> = Vec::new();
+
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            let volume = chapter
+                .metadata
+                .volume_id
+                .and_then(|volume_id| self.volumes.iter().find(|volume| volume.id == volume_id));
+
+            // Start a new group whenever the volume changes.
+            match groups.last_mut() {
+                Some((group_volume, group_chapters))
+                    if group_volume.map(|volume| volume.id) == volume.map(|volume| volume.id) =>
+                {
+                    group_chapters.push((i, chapter))
+                },
+                _ => groups.push((volume, vec![(i, chapter)])),
+            }
+        }
+
+        groups
     }
 
-    /// Count how many paragraphs are in the book.
-    pub fn count_paragraphs(&self) -> u128 {
-        // TODO!
-        0
+    /// Collect the author's notes of every chapter into an appendix html fragment, with a heading for each chapter.
+    ///
+    /// Returns None if none of the chapters have any notes.
+    pub fn author_notes_appendix(&self) -> Option<Html> {
+        let mut appendix = String::new();
+
+        for chapter in &self.chapters {
+            if chapter.pre_chapter_note.is_none() && chapter.post_chapter_note.is_none() {
+                continue;
+            }
+
+            appendix.push_str(&format!(
+                "<h2>{0}</h2>",
+                html::escape_html(&chapter.chapter_name)
+            ));
+            for note in [&chapter.pre_chapter_note, &chapter.post_chapter_note]
+                .into_iter()
+                .flatten()
+            {
+                appendix.push_str(&format!(
+                    r#"<div class="author-note">{0}</div>"#,
+                    note.root_element().inner_html()
+                ));
+            }
+        }
+
+        (!appendix.is_empty()).then(|| html::string_to_html_fragment(&appendix))
     }
+
+    /// Get the book's metadata as a list of labels & values that can be shown to the reader.
+    ///
+    /// Anything that couldn't be found on the index is left out.
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        let mut info: Vec<(&'static str, String)> = Vec::new();
+
+        if let Some(status) = self.status {
+            info.push(("Status", status.to_string()));
+        }
+        if !self.tags.is_empty() {
+            info.push(("Tags", self.tags.join(", ")));
+        }
+        if !self.content_warnings.is_empty() {
+            info.push(("Content warnings", self.content_warnings.join(", ")));
+        }
+        if let Some(pages) = self.stats.pages {
+            info.push(("Pages", pages.to_string()));
+        }
+        match (self.stats.rating, self.stats.ratings) {
+            (Some(rating), Some(ratings)) => {
+                info.push(("Rating", format!("{rating:.2} / 5 ({ratings} ratings)")))
+            },
+            (Some(rating), None) => info.push(("Rating", format!("{rating:.2} / 5"))),
+            _ => (),
+        }
+        if let Some(followers) = self.stats.followers {
+            info.push(("Followers", followers.to_string()));
+        }
+        if let Some(favorites) = self.stats.favorites {
+            info.push(("Favorites", favorites.to_string()));
+        }
+        if let Some(total_views) = self.stats.total_views {
+            info.push(("Total views", total_views.to_string()));
+        }
+        if let Some(average_views) = self.stats.average_views {
+            info.push(("Average views", average_views.to_string()));
+        }
+        if let Some(fiction_id) = self.fiction_id {
+            info.push(("Fiction ID", fiction_id.to_string()));
+        }
+
+        info
+    }
+}
+
+/// The status of a book as shown on RoyalRoad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FictionStatus {
+    Ongoing,
+    Completed,
+    Hiatus,
+    Stub,
+    Dropped,
+    Inactive,
+}
+
+impl std::fmt::Display for FictionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            FictionStatus::Ongoing => "Ongoing",
+            FictionStatus::Completed => "Completed",
+            FictionStatus::Hiatus => "Hiatus",
+            FictionStatus::Stub => "Stub",
+            FictionStatus::Dropped => "Dropped",
+            FictionStatus::Inactive => "Inactive",
+        };
+        write!(f, "{status}")
+    }
+}
+
+/// The statistics of a book as shown on its RoyalRoad index.
+///
+/// Any statistic that couldn't be found is None.
+#[derive(Debug, Clone, Default)]
+pub struct FictionStats {
+    /// How many times the book has been viewed in total.
+    pub total_views: Option<u64>,
+
+    /// The average views per chapter.
+    pub average_views: Option<u64>,
+
+    /// How many people follow the book.
+    pub followers: Option<u64>,
+
+    /// How many people have favorited the book.
+    pub favorites: Option<u64>,
+
+    /// How many people have rated the book.
+    pub ratings: Option<u64>,
+
+    /// The book's length in pages, as counted by RoyalRoad.
+    pub pages: Option<u64>,
+
+    /// The book's overall rating out of 5.
+    pub rating: Option<f64>,
+}
+
+/// A volume, or None for chapters outside of any volume, & its chapters along with their index in the book.
+pub type VolumeGroup<'a> = (Option<&'a Volume>, Vec<(usize, &'a Chapter)>);
+
+/// A volume that a book's chapters are grouped into.
+#[derive(Debug, Clone)]
+pub struct Volume {
+    /// RoyalRoad's ID for the volume. Chapters refer to their volume with it.
+    pub id: u64,
+
+    /// The title of the volume.
+    pub title: String,
+}
+
+/// The metadata of a chapter, as listed in the ``window.chapters`` json on the index.
+#[derive(Debug, Clone)]
+pub struct ChapterMetadata {
+    /// RoyalRoad's ID for the chapter.
+    pub id: u64,
+
+    /// The title of the chapter.
+    pub title: String,
+
+    /// The Url of the chapter.
+    pub url: Url,
+
+    /// When the chapter was published. None if the date couldn't be parsed.
+    pub date: Option<DateTime<Utc>>,
+
+    /// The position of the chapter in the book.
+    pub order: Option<u64>,
+
+    /// The ID of the volume the chapter is in, if the book is split into volumes.
+    pub volume_id: Option<u64>,
 }
 
 /// A struct representing a chapter.
 pub struct Chapter {
-    /// The Url of the chapter.
-    chapter_url: Url,
+    /// The chapter's metadata from the index.
+    pub metadata: ChapterMetadata,
 
     /// The name of the chapter.
     pub chapter_name: String,
 
-    /// The raw html data of the chapter page.
-    raw_chapter_html: Html,
-
     /// The isolated chapter html.
     pub isolated_chapter_html: Html,
+
+    /// The author's note shown before the chapter content, if there is one.
+    pub pre_chapter_note: Option<Html>,
+
+    /// The author's note shown after the chapter content, if there is one.
+    pub post_chapter_note: Option<Html>,
+
+    /// When the chapter was downloaded.
+    pub fetched_at: DateTime<Local>,
+
+    /// The ``etag`` header the chapter page was served with, used to tell if it changed by the next update.
+    pub etag: Option<String>,
+
+    /// The ``last-modified`` header the chapter page was served with, used to tell if it changed by the next update.
+    pub last_modified: Option<String>,
 }
 
 impl Chapter {
-    fn new(chapter_name: &str, chapter_url: &str) -> Result<Self, GenerationError> {
-        let chapter_url = http::string_to_url(&chapter_url)?;
-        let raw_chapter_html =
-            html::string_to_html_document(&http::get_response(chapter_url.clone())?.get_text()?);
+    /// Download a chapter page, making sure a cached copy is still up to date if revalidating.
+    ///
+    /// This is separate from ``Chapter::new`` so pages can be downloaded on other threads.
+    fn download_page(
+        fetcher: &dyn Fetcher,
+        chapter_url: &Url,
+        revalidate: bool,
+    ) -> Result<HttpResponse, GenerationError> {
+        if revalidate {
+            fetcher.fetch_fresh(chapter_url)
+        } else {
+            fetcher.fetch(chapter_url)
+        }
+    }
+
+    /// Generate a new chapter from its downloaded page.
+    pub(crate) fn new(
+        metadata: ChapterMetadata,
+        page: HttpResponse,
+    ) -> Result<Self, GenerationError> {
+        let etag = page.get_etag();
+        let last_modified = page.get_last_modified();
+        let raw_chapter_html = html::string_to_html_document(&page.get_text());
+        let mut isolated_chapter_html =
+            html::isolate_chapter_content(&raw_chapter_html, &metadata.url)?;
+        let (mut pre_chapter_note, mut post_chapter_note) =
+            html::get_author_notes(&raw_chapter_html);
+
+        // Strip out the anti-theft paragraphs. The page css that hides them is lost once the content is isolated.
+        let hidden_classes = html::get_hidden_classes(&raw_chapter_html);
+        html::remove_elements_with_classes(&mut isolated_chapter_html, &hidden_classes);
+        for note in [&mut pre_chapter_note, &mut post_chapter_note]
+            .into_iter()
+            .flatten()
+        {
+            html::remove_elements_with_classes(note, &hidden_classes);
+        }
 
         let chapter = Chapter {
-            isolated_chapter_html: html::isolate_chapter_content(&raw_chapter_html, &chapter_url)?,
-            chapter_url: chapter_url,
-            chapter_name: chapter_name.to_string(),
-            raw_chapter_html: raw_chapter_html,
+            isolated_chapter_html,
+            pre_chapter_note,
+            post_chapter_note,
+            chapter_name: metadata.title.clone(),
+            metadata,
+            fetched_at: Local::now(),
+            etag,
+            last_modified,
+        };
+
+        Ok(chapter)
+    }
+
+    /// Create a record of the chapter.
+    fn to_record(&self) -> ChapterRecord {
+        ChapterRecord {
+            chapter_url: self.metadata.url.clone(),
+            chapter_name: self.chapter_name.clone(),
+            fetched_at: self.fetched_at,
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
+        }
+    }
+
+    /// Get the chapter title to use as its heading, followed by the date it was published if wanted & known.
+    pub fn heading(&self, with_date: bool) -> String {
+        match self.metadata.date {
+            Some(date) if with_date => {
+                format!("{0} ({1})", self.chapter_name, date.format("%Y-%m-%d"))
+            },
+            _ => self.chapter_name.clone(),
+        }
+    }
+
+    /// Get the chapter html, with the author's notes placed before & after the content if they are shown inline.
+    pub fn html_with_author_notes(&self, author_notes: AuthorNotes) -> Html {
+        if author_notes != AuthorNotes::Inline
+            || (self.pre_chapter_note.is_none() && self.post_chapter_note.is_none())
+        {
+            return self.isolated_chapter_html.clone();
+        }
+
+        let note_html = |note: &Option<Html>| match note {
+            Some(note) => format!(
+                r#"<div class="author-note">{0}</div>"#,
+                note.root_element().inner_html()
+            ),
+            None => String::new(),
         };
 
-        return Ok(chapter);
+        html::string_to_html_fragment(&format!(
+            "{0}{1}{2}",
+            note_html(&self.pre_chapter_note),
+            self.isolated_chapter_html.root_element().inner_html(),
+            note_html(&self.post_chapter_note)
+        ))
     }
 }
@@ -0,0 +1,115 @@
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{GenerationError, Warning, WARNINGS};
+
+/// An on-disk cache of http responses keyed by Url.
+///
+/// Each entry is stored as two files named after the hash of the Url,
+/// ``<hash>.body`` containing the response bytes & ``<hash>.json`` containing the entry metadata.
+pub struct Cache {
+    directory: PathBuf,
+}
+
+/// The metadata stored alongside each cached response.
+#[derive(Serialize, Deserialize)]
+pub struct CacheEntryMetadata {
+    /// The Url the response was fetched from.
+    pub url: Url,
+
+    /// When the response was fetched or last revalidated.
+    pub fetched_at: DateTime<Local>,
+
+    /// The ``content-type`` header of the response.
+    pub content_type: Option<String>,
+
+    /// The ``etag`` header of the response. Used to revalidate the entry.
+    pub etag: Option<String>,
+
+    /// The ``last-modified`` header of the response. Used to revalidate the entry.
+    pub last_modified: Option<String>,
+}
+
+impl Cache {
+    /// Open the cache in the given directory, creating the directory if needed.
+    pub fn new(directory: PathBuf) -> Result<Cache, GenerationError> {
+        match fs::create_dir_all(&directory) {
+            Ok(_) => Ok(Cache { directory }),
+            Err(error) => Err(GenerationError::DirectoryCreationError {
+                error,
+                directory_path: directory,
+            }),
+        }
+    }
+
+    /// Get the paths of the body & metadata files for a Url.
+    fn entry_paths(&self, url: &Url) -> (PathBuf, PathBuf) {
+        let key = format!("{:x}", Sha256::digest(url.as_str().as_bytes()));
+
+        (
+            self.directory.join(format!("{key}.body")),
+            self.directory.join(format!("{key}.json")),
+        )
+    }
+
+    /// Get the metadata & body path of a cached Url. Returns None if the Url is not cached.
+    pub(crate) fn get(&self, url: &Url) -> Option<(CacheEntryMetadata, PathBuf)> {
+        let (body_path, metadata_path) = self.entry_paths(url);
+
+        // The metadata gets written last, so an entry without it was never finished.
+        let metadata_file = File::open(metadata_path).ok()?;
+        let metadata: CacheEntryMetadata = serde_json::from_reader(metadata_file).ok()?;
+
+        if metadata.url != *url || !body_path.exists() {
+            return None;
+        }
+
+        Some((metadata, body_path))
+    }
+
+    /// Store a response in the cache. Adds a warning if the entry can not be written.
+    pub(crate) fn store(&self, metadata: &CacheEntryMetadata, body: &[u8]) {
+        let (body_path, _) = self.entry_paths(&metadata.url);
+
+        let result = match fs::write(body_path, body) {
+            Ok(_) => self.write_metadata(metadata),
+            Err(error) => Err(error.to_string()),
+        };
+
+        if let Err(error) = result {
+            add_write_warning(&metadata.url, error);
+        }
+    }
+
+    /// Update the metadata of a cached Url, keeping the cached body.
+    pub(crate) fn update_metadata(&self, metadata: &CacheEntryMetadata) {
+        if let Err(error) = self.write_metadata(metadata) {
+            add_write_warning(&metadata.url, error);
+        }
+    }
+
+    /// Write the metadata file of an entry.
+    fn write_metadata(&self, metadata: &CacheEntryMetadata) -> Result<(), String> {
+        let (_, metadata_path) = self.entry_paths(&metadata.url);
+
+        let json = serde_json::to_vec(metadata).map_err(|error| error.to_string())?;
+        fs::write(metadata_path, json).map_err(|error| error.to_string())
+    }
+}
+
+/// Add a warning for a cache entry that could not be written.
+fn add_write_warning(url: &Url, error: String) {
+    let warning = Warning::CacheWriteError {
+        warning_msg: "Unable to write to the cache".to_string(),
+        url: url.clone(),
+        error,
+    };
+    WARNINGS.lock().unwrap().add_warning(warning);
+}
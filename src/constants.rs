@@ -1,3 +1,24 @@
+/// The default minimum delay between http requests in milliseconds.
+pub const DEFAULT_REQUEST_DELAY: u64 = 1000;
+
+/// The default number of times a failed http request is retried.
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// The default delay before the first retry of a failed http request in milliseconds.
+pub const DEFAULT_RETRY_BACKOFF: u64 = 2000;
+
+/// The default http request timeout in seconds.
+pub const DEFAULT_TIMEOUT: u64 = 30;
+
+/// The default number of downloads that run at the same time.
+pub const DEFAULT_JOBS: usize = 4;
+
+/// The longest the exponential backoff between retries can grow to in seconds.
+pub const MAX_RETRY_BACKOFF: u64 = 120;
+
+/// What scene breaks (`<hr>` tags) turn into in plain text.
+pub const TEXT_SCENE_BREAK: &str = "* * *";
+
 pub const EPUB_XML_HEAD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
 <!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
 <html xmlns="http://www.w3.org/1999/xhtml">"#;
@@ -107,4 +128,142 @@ hr {
 input, select {
     vertical-align:middle;
 }
+
+.author-note {
+    margin:1em 0;
+    padding:0.5em;
+    border:1px solid #cccccc;
+    font-style:italic;
+}
+
+.image-placeholder {
+    display:block;
+    padding:0.5em;
+    border:1px dashed #999999;
+    font-size:0.8em;
+}
+"#;
+
+pub const HTML_CSS: &str = r#"
+body {
+    max-width: 50em;
+    margin: 0 auto;
+    padding: 1em;
+    font-family: Georgia, "Times New Roman", serif;
+    line-height: 1.6;
+    color: #222222;
+    background: #fdfdfd;
+}
+
+a {
+    color: #1a5fb4;
+}
+
+img {
+    max-width: 100%;
+    height: auto;
+}
+
+hr {
+    border: 0;
+    border-top: 1px solid #cccccc;
+    margin: 1em 0;
+}
+
+table {
+    border-collapse: collapse;
+}
+
+td, th {
+    border: 1px solid #cccccc;
+    padding: 0.25em 0.5em;
+}
+
+.cover {
+    text-align: center;
+}
+
+.cover img {
+    max-height: 30em;
+}
+
+.book-info {
+    display: grid;
+    grid-template-columns: max-content auto;
+    gap: 0.25em 1em;
+}
+
+.book-info dt {
+    font-weight: bold;
+}
+
+.book-info dd {
+    margin: 0;
+}
+
+.chapter-list li {
+    margin: 0.25em 0;
+}
+
+.navigation {
+    display: flex;
+    justify-content: space-between;
+    margin: 2em 0;
+}
+
+.author-note {
+    margin: 1em 0;
+    padding: 0.5em 1em;
+    border-left: 4px solid #cccccc;
+    background: #f4f4f4;
+    font-style: italic;
+}
+
+.image-placeholder {
+    display: block;
+    padding: 0.5em 1em;
+    border: 1px dashed #999999;
+    color: #666666;
+    font-size: 0.9em;
+    word-break: break-all;
+}
+
+.volume-title {
+    margin-top: 3em;
+    text-align: center;
+}
+
+.chapter {
+    margin-top: 3em;
+}
+
+/* Follow the system's dark mode. */
+:root {
+    color-scheme: light dark;
+}
+
+@media (prefers-color-scheme: dark) {
+    body {
+        color: #dddddd;
+        background: #1e1e1e;
+    }
+
+    a {
+        color: #78aeed;
+    }
+
+    hr, td, th {
+        border-color: #555555;
+    }
+
+    .author-note {
+        border-left-color: #555555;
+        background: #2a2a2a;
+    }
+
+    .image-placeholder {
+        border-color: #777777;
+        color: #aaaaaa;
+    }
+}
 "#;
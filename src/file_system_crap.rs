@@ -1,9 +1,12 @@
-use std::{io::Cursor, path::PathBuf};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use path_slash::PathBufExt as _;
-use tempfile::TempDir;
 
-use crate::{misc, GenerationError, Warning, WARNINGS};
+use crate::GenerationError;
 
 /// Converts a given path to windows style if needed.
 pub fn convert_path_to_os_specific(path: PathBuf) -> PathBuf {
@@ -16,7 +19,7 @@ pub fn convert_path_to_os_specific(path: PathBuf) -> PathBuf {
     // If target os is not windows.
     #[cfg(not(target_os = "windows"))]
     {
-        return PathBuf::from_backslash_lossy(path.into_os_string());
+        PathBuf::from_backslash_lossy(path.into_os_string())
     }
 }
 
@@ -28,73 +31,61 @@ pub fn remove_illegal_chars(mut string: String) -> String {
         string = string.replace(char, " ");
     }
 
-    return string;
+    string
 }
 
-/// Setup html2xhtml in the operating system's temp directory.
-pub fn setup_html2xhtml() -> Result<TempDir, GenerationError> {
-    #[cfg(target_os = "windows")]
-    {
-        const HTML2XHTML: &[u8; 245025] = include_bytes!("../html2xhtml-windows.zip"); // This will not compile on windows due to this and no I don't give a shit.
-                                                                                       // Compile it on linux for windows like a sane person.
-        let html2xhtml_temp_dir = create_temp_dir()?;
-
-        match zip_extract::extract(Cursor::new(HTML2XHTML), html2xhtml_temp_dir.path(), true) {
-            Ok(_) => (),
-            Err(error) => return Err(GenerationError::Html2XhtmlExtractionError { error }),
-        }
-
-        return Ok(html2xhtml_temp_dir);
-    }
-
-    #[cfg(target_os = "linux")]
-    {
-        const HTML2XHTML: &[u8; 186938] = include_bytes!("../html2xhtml-linux.zip");
-        let html2xhtml_temp_dir = create_temp_dir()?;
-
-        match zip_extract::extract(Cursor::new(HTML2XHTML), html2xhtml_temp_dir.path(), true) {
-            Ok(_) => (),
-            Err(error) => return Err(GenerationError::Html2XhtmlExtractionError { error }),
-        }
-
-        return Ok(html2xhtml_temp_dir);
+/// Create a new directory. Returns an error if it already exists or can not be created.
+pub fn create_new_directory(directory_path: &Path) -> Result<(), GenerationError> {
+    match fs::create_dir(directory_path) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(GenerationError::DirectoryCreationError {
+            error,
+            directory_path: directory_path.to_path_buf(),
+        }),
     }
+}
 
-    #[cfg(target_os = "macos")]
-    {
-        Err(GenerationError::OsUnsupportedError {
-            os: misc::Oses::MacOs,
-        })
+/// Hard link a file to a new path, falling back to copying it if it can't be linked (E.G. it's on another drive).
+/// Returns an error if the new path already exists.
+pub fn link_or_copy_file(source_path: &Path, file_path: &Path) -> Result<(), GenerationError> {
+    if fs::hard_link(source_path, file_path).is_ok() {
+        return Ok(());
     }
 
-    // In the event the OS is unknown.
-    #[allow(unreachable_code)]
-    Err(GenerationError::OsUnsupportedError {
-        os: misc::Oses::OtherUnknownOs,
-    })
-}
+    let data = match fs::read(source_path) {
+        Ok(data) => data,
+        Err(error) => {
+            return Err(GenerationError::FileReadError {
+                error,
+                file_path: source_path.to_path_buf(),
+            })
+        },
+    };
 
-/// Function to create a temporary directory.
-fn create_temp_dir() -> Result<TempDir, GenerationError> {
-    match TempDir::new() {
-        Ok(temp_dir) => return Ok(temp_dir),
-        Err(error) => return Err(GenerationError::TempDirCreationError { error }),
-    }
+    write_new_file(file_path, &data)
 }
 
-/// Delete a temporary directory.
-pub fn delete_temp_dir(temp_dir: TempDir) {
-    let temp_dir_path = temp_dir.path().to_path_buf();
-
-    match temp_dir.close() {
-        Ok(_) => (),
-        Err(warning) => {
-            let warning = Warning::TempDirDeletionError {
-                warning_msg: "Unable to close and delete temp directory".to_string(),
-                temp_directory_path: temp_dir_path,
-                error: warning,
-            };
-            WARNINGS.lock().unwrap().add_warning(warning);
+/// Create a new file and write the given data to it. Returns an error if the file already exists.
+pub fn write_new_file(file_path: &Path, data: &[u8]) -> Result<(), GenerationError> {
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(file_path)
+    {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(GenerationError::FileCreationError {
+                error,
+                file_path: file_path.to_path_buf(),
+            })
         },
+    };
+
+    match file.write_all(data) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(GenerationError::FileWriteError {
+            error,
+            file_path: file_path.to_path_buf(),
+        }),
     }
 }
@@ -1,15 +1,18 @@
-use std::{
-    collections::HashMap,
-    io::Write,
-    process::{Command, Stdio},
-};
+use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
+use ego_tree::{NodeId, NodeMut, NodeRef};
 use regex::Regex;
-use scraper::{Html, Selector};
-use tempfile::TempDir;
+use scraper::{Html, Node, Selector};
+use serde::Deserialize;
 use url::Url;
 
-use crate::{http, misc::HashMapExt, GenerationError, Warning, WARNINGS};
+use crate::{
+    book::{ChapterMetadata, FictionStats, FictionStatus, Volume},
+    constants, http,
+    misc::HashMapExt,
+    GenerationError, Warning, WARNINGS,
+};
 
 /// Convert a string to an html document.
 pub fn string_to_html_document(document_string: &str) -> Html {
@@ -82,61 +85,229 @@ pub fn get_cover_image_url_from_index(
     })
 }
 
-/// Gets the chapter names and urls from the index.
+/// Get the book's description from the index.
+pub fn get_description_from_index(index_html: &Html) -> Option<Html> {
+    let selector = Selector::parse("div.description").unwrap();
+    index_html
+        .select(&selector)
+        .next()
+        .map(|element| string_to_html_fragment(&element.inner_html()))
+}
+
+/// Get the book's fiction ID from the index, falling back to the one in the book url.
+pub fn get_fiction_id_from_index(index_html: &Html, book_url: &Url) -> Option<u64> {
+    let fiction_id_regex = Regex::new(r"window\.fictionId\s*=\s*(\d+)").unwrap();
+
+    let selector = Selector::parse("script").unwrap();
+    for element in index_html.select(&selector) {
+        if let Some(captures) = fiction_id_regex.captures(&element.inner_html()) {
+            return captures[1].parse().ok();
+        }
+    }
+
+    // Book urls look like https://www.royalroad.com/fiction/<id>/<name>
+    let mut path_segments = book_url.path_segments()?;
+    match path_segments.next() {
+        Some("fiction") => path_segments.next()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Get the book's genre tags from the index.
+pub fn get_tags_from_index(index_html: &Html) -> Vec<String> {
+    let selector = Selector::parse("span.tags a.fiction-tag").unwrap();
+    index_html
+        .select(&selector)
+        .map(|element| element.text().collect::<String>().trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// Get the book's content warnings from the index.
+pub fn get_content_warnings_from_index(index_html: &Html) -> Vec<String> {
+    let selector = Selector::parse(".font-red-sunglo ul.list-inline li").unwrap();
+    index_html
+        .select(&selector)
+        .map(|element| element.text().collect::<String>().trim().to_string())
+        .filter(|content_warning| !content_warning.is_empty())
+        .collect()
+}
+
+/// Get the book's status from the labels on the index.
+pub fn get_status_from_index(index_html: &Html) -> Option<FictionStatus> {
+    let selector = Selector::parse(".fiction-info span.label").unwrap();
+    index_html.select(&selector).find_map(|element| {
+        match element
+            .text()
+            .collect::<String>()
+            .trim()
+            .to_uppercase()
+            .as_str()
+        {
+            "ONGOING" => Some(FictionStatus::Ongoing),
+            "COMPLETED" => Some(FictionStatus::Completed),
+            "HIATUS" => Some(FictionStatus::Hiatus),
+            "STUB" => Some(FictionStatus::Stub),
+            "DROPPED" => Some(FictionStatus::Dropped),
+            "INACTIVE" => Some(FictionStatus::Inactive),
+            _ => None,
+        }
+    })
+}
+
+/// Get the book's statistics from the index.
 ///
-/// This gets stored in a vector where index 0 is the chapter name, and index 1 is the url.
-pub fn get_chapter_names_and_urls_from_index(
+/// Any statistic that can't be found is left as None.
+pub fn get_stats_from_index(index_html: &Html) -> FictionStats {
+    let mut stats = FictionStats::default();
+
+    // The stats are a list where every label item is followed by an item holding its value. E.G. "Followers :", "1,234"
+    let selector = Selector::parse(".stats-content li").unwrap();
+    let items: Vec<String> = index_html
+        .select(&selector)
+        .map(|element| element.text().collect::<String>().trim().to_string())
+        .collect();
+
+    for pair in items.chunks_exact(2) {
+        let label = pair[0].trim_end_matches(':').trim().to_lowercase();
+        let value = pair[1].replace(',', "").parse::<u64>().ok();
+
+        match label.as_str() {
+            "total views" => stats.total_views = value,
+            "average views" => stats.average_views = value,
+            "followers" => stats.followers = value,
+            "favorites" => stats.favorites = value,
+            "ratings" => stats.ratings = value,
+            "pages" => stats.pages = value,
+            _ => (),
+        }
+    }
+
+    // The overall score isn't in the list, but it is in the meta tags.
+    let selector = Selector::parse(r#"meta[property="books:rating:value"]"#).unwrap();
+    stats.rating = index_html
+        .select(&selector)
+        .next()
+        .and_then(|element| element.value().attr("content"))
+        .and_then(|rating| rating.parse().ok());
+
+    stats
+}
+
+/// A chapter entry in the ``window.chapters`` json on the index, as RoyalRoad writes it.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawChapterEntry {
+    id: u64,
+    title: String,
+    url: String,
+    date: Option<String>,
+    order: Option<u64>,
+    volume_id: Option<u64>,
+}
+
+/// Gets the metadata of every chapter from the ``window.chapters`` json on the index.
+pub fn get_chapter_metadata_from_index(
     index_html: &Html,
     book_url: &Url,
-) -> Result<Vec<(String, String)>, GenerationError> {
+) -> Result<Vec<ChapterMetadata>, GenerationError> {
     // I wont lie. I have almost 0 idea what a bunch of this shit does since it's highly specific to RoyalRoad.
     // I've commented in the gist of it, but we have no memory actually writing this function.
 
-    let mut chapters: Vec<(String, String)> = Vec::new();
     let mut raw_json_data = String::new();
 
-    // Find a script tag that has "window.chapters" inside the inner html. This is all in json format.
+    // Find a script tag that has "window.chapters" inside it. This is all in json format.
+    // Use the raw text so characters like & don't get escaped.
     let selector = Selector::parse("script").unwrap();
     for element in index_html.select(&selector) {
-        if element.inner_html().contains("window.chapters") {
-            raw_json_data = element.inner_html();
+        let script = element.text().collect::<String>();
+        if script.contains("window.chapters") {
+            raw_json_data = script;
             break;
         }
     }
     // Exit it if unable to find the needed json data. That probably means royal road has changed their code.
+    let chapter_fetch_error = || GenerationError::BookChapterNameAndUrlFetchError {
+        url: book_url.clone(),
+    };
     if raw_json_data.is_empty() {
-        return Err(GenerationError::BookChapterNameAndUrlFetchError {
-            url: book_url.clone(),
-        });
+        return Err(chapter_fetch_error());
     }
 
-    // I have absolutely no idea what this regex does; but it's probably important.
+    // Grab the json array assigned to window.chapters.
     const REGEX: &str = r#"window.chapters = (\[.*?]);"#;
     let regex = Regex::new(REGEX).unwrap();
 
-    // I still have no fucking clue what this magic part does; but it works so we ain't fucking touching it.
-    let chapter_raw_json = regex
-        .captures(&raw_json_data)
-        .unwrap()
-        .get(1)
-        .map_or("[]", |m| m.as_str());
+    let chapter_raw_json = match regex.captures(&raw_json_data) {
+        Some(captures) => captures.get(1).map_or("[]", |m| m.as_str()),
+        None => return Err(chapter_fetch_error()),
+    };
 
     // and it just spits out json when done. Neat.
-    let chapter_json: serde_json::Value = serde_json::from_str(chapter_raw_json).unwrap();
-
-    // For each chapter in the json, do some processing to remove the quotes then shove it onto the vector.
-    for chapter in chapter_json.as_array().unwrap() {
-        let chapter_name = chapter["title"].to_string().replace('"', "");
-        let url = format!(
-            "https://www.royalroad.com{}",
-            chapter["url"].to_string().replace('"', "")
-        );
+    let raw_chapters: Vec<RawChapterEntry> = match serde_json::from_str(chapter_raw_json) {
+        Ok(raw_chapters) => raw_chapters,
+        Err(_) => return Err(chapter_fetch_error()),
+    };
+
+    // Turn the relative urls into full ones & parse the dates.
+    let mut chapters: Vec<ChapterMetadata> = Vec::with_capacity(raw_chapters.len());
+    for raw_chapter in raw_chapters {
+        let url = http::string_to_url(&format!("https://www.royalroad.com{0}", raw_chapter.url))?;
+        let date = raw_chapter
+            .date
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+            .map(|date| date.with_timezone(&Utc));
 
-        chapters.push((chapter_name, url));
+        chapters.push(ChapterMetadata {
+            id: raw_chapter.id,
+            title: raw_chapter.title,
+            url,
+            date,
+            order: raw_chapter.order,
+            volume_id: raw_chapter.volume_id,
+        });
     }
 
     // Return that wanker.
-    return Ok(chapters);
+    Ok(chapters)
+}
+
+/// A volume entry in the ``window.volumes`` json on the index, as RoyalRoad writes it.
+#[derive(Deserialize)]
+struct RawVolumeEntry {
+    id: u64,
+    title: String,
+    order: Option<u64>,
+}
+
+/// Gets the volumes the book is split into from the ``window.volumes`` json on the index, sorted by their order.
+///
+/// Not every book has volumes, so this is empty if there aren't any.
+pub fn get_volumes_from_index(index_html: &Html) -> Vec<Volume> {
+    let regex = Regex::new(r#"window.volumes = (\[.*?]);"#).unwrap();
+
+    let selector = Selector::parse("script").unwrap();
+    for element in index_html.select(&selector) {
+        let script = element.text().collect::<String>();
+        let volume_raw_json = match regex.captures(&script) {
+            Some(captures) => captures.get(1).map_or("[]", |m| m.as_str()).to_string(),
+            None => continue,
+        };
+
+        let mut raw_volumes: Vec<RawVolumeEntry> =
+            serde_json::from_str(&volume_raw_json).unwrap_or_default();
+        raw_volumes.sort_by_key(|raw_volume| raw_volume.order);
+
+        return raw_volumes
+            .into_iter()
+            .map(|raw_volume| Volume {
+                id: raw_volume.id,
+                title: raw_volume.title,
+            })
+            .collect();
+    }
+
+    Vec::new()
 }
 
 /// Isolate chapter content from the rest of the shit on the page.
@@ -162,6 +333,148 @@ pub fn isolate_chapter_content(
     })
 }
 
+/// Get the classes that the inline style rules of a page hide with ``display: none``.
+///
+/// RoyalRoad uses these to hide the "stolen from Royal Road" paragraphs it injects into chapters.
+pub fn get_hidden_classes(raw_chapter_html: &Html) -> Vec<String> {
+    let comment_regex = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+    let display_none_regex = Regex::new(r"display\s*:\s*none").unwrap();
+    let class_regex = Regex::new(r"\.([A-Za-z0-9_-]+)").unwrap();
+
+    let mut hidden_classes: Vec<String> = Vec::new();
+
+    let selector = Selector::parse("style").unwrap();
+    for element in raw_chapter_html.select(&selector) {
+        let css = element.inner_html();
+        let css = comment_regex.replace_all(&css, "");
+
+        // Rules nested one level deep in at-rules like @media or @supports count too.
+        let mut rules: Vec<(&str, &str)> = Vec::new();
+        for (prelude, block) in split_css_rules(&css) {
+            if prelude.trim_start().starts_with('@') {
+                rules.extend(split_css_rules(block));
+            } else {
+                rules.push((prelude, block));
+            }
+        }
+
+        // Grab every class in the selectors of the rules that hide things.
+        for (selector, declarations) in rules {
+            if !display_none_regex.is_match(declarations) {
+                continue;
+            }
+
+            for class in class_regex.captures_iter(selector) {
+                if !hidden_classes.contains(&class[1].to_string()) {
+                    hidden_classes.push(class[1].to_string());
+                }
+            }
+        }
+    }
+
+    hidden_classes
+}
+
+/// Split css into its top level rules, as the text before each block & the text inside the block.
+///
+/// Blocks nested inside a rule (like the rules of an @media query) are left in its block.
+/// At-rules without a block, like @import, are skipped.
+fn split_css_rules(css: &str) -> Vec<(&str, &str)> {
+    let mut rules: Vec<(&str, &str)> = Vec::new();
+    let mut prelude_start = 0;
+    let mut block_start = 0;
+    let mut depth = 0;
+
+    for (i, char) in css.char_indices() {
+        match char {
+            '{' => {
+                if depth == 0 {
+                    block_start = i;
+                }
+                depth += 1;
+            },
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    rules.push((&css[prelude_start..block_start], &css[block_start + 1..i]));
+                    prelude_start = i + 1;
+                }
+            },
+            ';' if depth == 0 => prelude_start = i + 1,
+            _ => (),
+        }
+    }
+
+    rules
+}
+
+/// Remove every element that has one of the given classes from the html.
+pub fn remove_elements_with_classes(html: &mut Html, classes: &[String]) {
+    if classes.is_empty() {
+        return;
+    }
+
+    let element_ids: Vec<NodeId> = html
+        .tree
+        .nodes()
+        .filter(|node| match node.value() {
+            Node::Element(element) => element
+                .classes()
+                .any(|class| classes.iter().any(|hidden_class| hidden_class == class)),
+            _ => false,
+        })
+        .map(|node| node.id())
+        .collect();
+
+    for element_id in element_ids {
+        html.tree.get_mut(element_id).unwrap().detach();
+    }
+}
+
+/// Get the author's notes from a chapter page.
+///
+/// Returns the notes placed before the chapter content & the notes placed after it, if there are any.
+pub fn get_author_notes(raw_chapter_html: &Html) -> (Option<Html>, Option<Html>) {
+    let selector =
+        Selector::parse("div.author-note-portlet, div.chapter-inner.chapter-content").unwrap();
+    let note_body_selector = Selector::parse("div.author-note").unwrap();
+
+    let mut pre_chapter_notes = String::new();
+    let mut post_chapter_notes = String::new();
+    let mut found_content = false;
+
+    // The elements come out in the order they are on the page, so any note before the content is a pre-chapter note.
+    for element in raw_chapter_html.select(&selector) {
+        if element.value().has_class(
+            "chapter-content",
+            scraper::CaseSensitivity::AsciiCaseInsensitive,
+        ) {
+            found_content = true;
+            continue;
+        }
+
+        // Only keep the body of the note, not the "A note from ..." caption.
+        let note = match element.select(&note_body_selector).next() {
+            Some(note_body) => note_body.inner_html(),
+            None => element.inner_html(),
+        };
+
+        if found_content {
+            post_chapter_notes.push_str(&note);
+        } else {
+            pre_chapter_notes.push_str(&note);
+        }
+    }
+
+    let to_fragment =
+        |notes: String| (!notes.trim().is_empty()).then(|| string_to_html_fragment(&notes));
+
+    (
+        to_fragment(pre_chapter_notes),
+        to_fragment(post_chapter_notes),
+    )
+}
+
 /// Remove all img tags from the html fragment.
 pub fn remove_image_tags(html_fragment: &Html) -> String {
     let mut image_tags: Vec<String> = Vec::new();
@@ -179,7 +492,7 @@ pub fn remove_image_tags(html_fragment: &Html) -> String {
         html_fragment = html_fragment.replace(&image_tag, "");
     }
 
-    return html_fragment;
+    html_fragment
 }
 
 /// Extract the urls and image tags from a chapter and put them in the hashmap:
@@ -214,68 +527,547 @@ pub fn extract_urls_and_img_tag(chapter_html: &Html) -> HashMap<Url, Vec<String>
         chapter_image_urls = chapter_image_urls.join(temp_map);
     }
 
-    return chapter_image_urls;
+    chapter_image_urls
 }
 
-/// Replace the image tag with new one that contains the new src attribute.
-pub fn replace_img_src(img_tag: String, new_src: String) -> String {
-    let img_tag = string_to_html_fragment(&img_tag);
+/// Rewrite the images in some html in document order, pointing each at a new src or swapping it for a placeholder.
+///
+/// The new src for an image is looked up by its url. If there isn't one the image couldn't be downloaded,
+/// so it gets a placeholder instead. Images without a valid url are left alone.
+pub fn replace_images(html: &Html, new_src: impl Fn(&Url) -> Option<String>) -> Html {
+    let mut html = html.clone();
 
-    let selector = Selector::parse("img").unwrap();
-    let element = img_tag.select(&selector).next().unwrap();
+    let image_ids: Vec<NodeId> = html
+        .tree
+        .root()
+        .descendants()
+        .filter(|node| matches!(node.value(), Node::Element(element) if element.name() == "img"))
+        .map(|node| node.id())
+        .collect();
 
-    if element.attr("src").is_some() {
-        let image_tag = element.html();
+    for image_id in image_ids {
+        let Node::Element(element) = html.tree.get(image_id).unwrap().value() else {
+            continue;
+        };
+        let image_url = match element.attr("src").map(Url::parse) {
+            Some(Ok(image_url)) => image_url,
+            _ => continue,
+        };
+
+        match new_src(&image_url) {
+            Some(new_src) => {
+                let mut image = html.tree.get_mut(image_id).unwrap();
+                if let Node::Element(element) = image.value() {
+                    for (name, value) in element.attrs.iter_mut() {
+                        if &name.local == "src" {
+                            *value = new_src.as_str().into();
+                        }
+                    }
+                }
+            },
+            None => {
+                let placeholder =
+                    string_to_html_fragment(&image_placeholder(&image_url, element.attr("alt")));
+
+                let mut image = html.tree.get_mut(image_id).unwrap();
+                for node in placeholder.root_element().children() {
+                    let mut copy = image.insert_before(node.value().clone());
+                    append_copies(&mut copy, node);
+                }
+                image.detach();
+            },
+        }
+    }
+
+    html
+}
+
+/// Append copies of the children of a node from another tree, and all their children.
+fn append_copies(parent: &mut NodeMut<Node>, node: NodeRef<Node>) {
+    for child in node.children() {
+        let mut copy = parent.append(child.value().clone());
+        append_copies(&mut copy, child);
+    }
+}
+
+/// Build a placeholder for an image that couldn't be downloaded, linking to where the image was.
+pub fn image_placeholder(image_url: &Url, alt_text: Option<&str>) -> String {
+    let alt_text = match alt_text {
+        Some(alt_text) if !alt_text.trim().is_empty() => format!(": {}", escape_html(alt_text)),
+        _ => String::new(),
+    };
+
+    format!(
+        r#"<div class="image-placeholder">Image unavailable{alt_text}<br/><a href="{0}">{0}</a></div>"#,
+        escape_html(image_url.as_str())
+    )
+}
+
+/// Escape the characters in a string that have special meaning in html.
+pub fn escape_html(string: &str) -> String {
+    string
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Convert an html fragment into plain text.
+///
+/// Block level elements become paragraphs separated by a blank line, and all other whitespace is collapsed.
+/// Tables become a paragraph with a line per row, and their cells separated by a `|`.
+/// Images, scripts & styles are dropped.
+pub fn html_to_text(html: &Html) -> String {
+    html_to_text_with_scene_breaks(html, None)
+}
+
+/// Convert an html fragment into plain text like [html_to_text], putting the scene break text in place of each `<hr>`.
+pub fn html_to_text_with_scene_breaks(html: &Html, scene_break: Option<&str>) -> String {
+    let mut paragraphs: Vec<String> = Vec::new();
+    let mut current_paragraph = String::new();
+
+    collect_text(
+        html.tree.root(),
+        scene_break,
+        &mut paragraphs,
+        &mut current_paragraph,
+    );
+    push_paragraph(&mut paragraphs, &mut current_paragraph);
+
+    paragraphs.join("\n\n")
+}
+
+/// Recursively walk the html tree collecting text into paragraphs.
+fn collect_text(
+    node: NodeRef<Node>,
+    scene_break: Option<&str>,
+    paragraphs: &mut Vec<String>,
+    current_paragraph: &mut String,
+) {
+    const BLOCK_ELEMENTS: [&str; 24] = [
+        "address",
+        "article",
+        "aside",
+        "blockquote",
+        "br",
+        "caption",
+        "dd",
+        "div",
+        "dl",
+        "dt",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "hr",
+        "li",
+        "ol",
+        "p",
+        "pre",
+        "section",
+        "table",
+        "tr",
+    ];
+
+    for child in node.children() {
+        match child.value() {
+            Node::Text(text) => {
+                // Text is only separated from what came before it where the html has whitespace,
+                // so inline elements like <em>Really</em>? or <b>wo</b>rd don't gain spaces.
+                if text.starts_with(char::is_whitespace) {
+                    push_space(current_paragraph);
+                }
+                for (i, word) in text.split_whitespace().enumerate() {
+                    if i > 0 {
+                        current_paragraph.push(' ');
+                    }
+                    current_paragraph.push_str(word);
+                }
+                if text.ends_with(char::is_whitespace) {
+                    push_space(current_paragraph);
+                }
+            },
+            Node::Element(element) => {
+                let name = element.name();
+                if matches!(name, "script" | "style" | "img") {
+                    continue;
+                }
+
+                let is_block = BLOCK_ELEMENTS.contains(&name);
+                if is_block {
+                    push_paragraph(paragraphs, current_paragraph);
+                }
+
+                match name {
+                    "hr" => {
+                        if let Some(scene_break) = scene_break {
+                            paragraphs.push(scene_break.to_string());
+                        }
+                    },
+                    // Keep the rows of a table together, one per line.
+                    "table" => {
+                        let mut rows: Vec<String> = Vec::new();
+                        let mut current_row = String::new();
+                        collect_text(child, scene_break, &mut rows, &mut current_row);
+                        push_paragraph(&mut rows, &mut current_row);
+
+                        if !rows.is_empty() {
+                            paragraphs.push(rows.join("\n"));
+                        }
+                        continue;
+                    },
+                    // Every cell after the first in a row gets separated from the one before it.
+                    "td" | "th" if !current_paragraph.trim_end().is_empty() => {
+                        current_paragraph.truncate(current_paragraph.trim_end().len());
+                        current_paragraph.push_str(" | ");
+                    },
+                    _ => (),
+                }
+
+                collect_text(child, scene_break, paragraphs, current_paragraph);
+
+                if is_block {
+                    push_paragraph(paragraphs, current_paragraph);
+                }
+            },
+            _ => (),
+        }
+    }
+}
 
-        let src_match_regex = Regex::new(r#"(src=["'].*["'])"#).unwrap();
-        let src_attr = src_match_regex
-            .captures(&image_tag)
-            .unwrap()
-            .get(0)
-            .map(|m| m.as_str())
-            .unwrap();
+/// Add a space to the end of the current paragraph, unless it's empty or already ends with one.
+fn push_space(current_paragraph: &mut String) {
+    if !current_paragraph.is_empty() && !current_paragraph.ends_with(' ') {
+        current_paragraph.push(' ');
+    }
+}
 
-        return image_tag.replace(src_attr, &format!(r#"src="{new_src}""#));
-    } else {
-        return element.html();
+/// Push the current paragraph onto the paragraphs vector if it contains anything, dropping any trailing space.
+fn push_paragraph(paragraphs: &mut Vec<String>, current_paragraph: &mut String) {
+    current_paragraph.truncate(current_paragraph.trim_end().len());
+    if !current_paragraph.is_empty() {
+        paragraphs.push(std::mem::take(current_paragraph));
     }
 }
 
-/// Convert a given html dom into xhtml.
-pub fn html_to_xhtml(html: Html, html2xhtml_dir: &TempDir) -> Result<String, GenerationError> {
-    #[cfg(target_os = "windows")]
-    const HTML2XHTML_ENTRY: &str = "html2xhtml.exe";
+/// Wrap the given body html in a full html page that uses the archive stylesheet.
+pub fn wrap_html_page(title: &str, body: &str) -> String {
+    wrap_html_page_with_head(title, r#"<link rel="stylesheet" href="style.css"/>"#, body)
+}
 
-    #[cfg(target_os = "linux")]
-    const HTML2XHTML_ENTRY: &str = "html2xhtml";
+/// Wrap the given body html in a full html page with the archive stylesheet embedded in it, so it needs no other files.
+pub fn wrap_self_contained_html_page(title: &str, body: &str) -> String {
+    wrap_html_page_with_head(
+        title,
+        &format!("<style>{0}</style>", constants::HTML_CSS),
+        body,
+    )
+}
 
-    #[cfg(target_os = "macos")]
-    const HTML2XHTML_ENTRY: &str = "html2xhtml";
+/// Wrap the given body html in a full html page, adding the extra head html after the title.
+fn wrap_html_page_with_head(title: &str, head: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8"/>
+<meta name="viewport" content="width=device-width, initial-scale=1"/>
+<title>{0}</title>
+{head}
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        escape_html(title)
+    )
+}
 
-    // Remove nbsp, They can cause certain e-readers to crash.
-    let html = html.html().replace("&nbsp;", " ");
+/// Convert a given html dom into a complete xhtml document for an epub.
+///
+/// Only the body of the html is kept. The document links to the epub stylesheet.
+pub fn html_to_xhtml(html: &Html, title: &str) -> String {
+    let mut body = String::new();
 
-    // Start html2xhtml.
-    let mut html2xhtml = match Command::new(html2xhtml_dir.path().join(HTML2XHTML_ENTRY))
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-    {
-        Ok(child) => child,
-        Err(error) => return Err(GenerationError::Html2XhtmlStartError { error }),
+    // Documents have their content in the body, fragments straight in the html element.
+    let body_selector = Selector::parse("body").unwrap();
+    let container = match html.select(&body_selector).next() {
+        Some(body) => *body,
+        None => *html.root_element(),
     };
+    for child in container.children() {
+        write_xhtml_node(child, &mut body);
+    }
+
+    format!(
+        r#"{0}
+<head>
+<title>{1}</title>
+<link rel="stylesheet" type="text/css" href="stylesheet.css"/>
+</head>
+<body>
+{body}
+</body>
+{2}"#,
+        constants::EPUB_XML_HEAD,
+        escape_xml(title),
+        constants::EPUB_XML_TAIL
+    )
+}
+
+/// Recursively write a node & its children as well formed xhtml.
+fn write_xhtml_node(node: NodeRef<Node>, xhtml: &mut String) {
+    // Elements that can't have any content, so they have to be self closing.
+    const VOID_ELEMENTS: [&str; 14] = [
+        "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param",
+        "source", "track", "wbr",
+    ];
+
+    match node.value() {
+        Node::Text(text) => xhtml.push_str(&escape_xml(text)),
+        Node::Element(element) => {
+            let name = element.name();
+            // Scripts have no place in an epub.
+            if name == "script" {
+                return;
+            }
+
+            // Elements xml can't name, like the <o:p> word pastes are full of, get unwrapped & only their content is kept.
+            if !is_valid_xml_name(name) {
+                for child in node.children() {
+                    write_xhtml_node(child, xhtml);
+                }
+                return;
+            }
+
+            xhtml.push('<');
+            xhtml.push_str(name);
+
+            let mut written_attributes: Vec<&str> = Vec::new();
+            for (attribute_name, value) in element.attrs() {
+                // Xml doesn't allow repeated attributes, or names html parsers are happy to accept like "a<b".
+                // Prefixed names like "o:spid" are dropped too, since their namespaces are never declared.
+                if written_attributes.contains(&attribute_name)
+                    || !is_valid_xml_name(attribute_name)
+                {
+                    continue;
+                }
+                written_attributes.push(attribute_name);
+
+                xhtml.push_str(&format!(r#" {attribute_name}="{0}""#, escape_xml(value)));
+            }
+
+            if VOID_ELEMENTS.contains(&name) {
+                xhtml.push_str("/>");
+                return;
+            }
+            xhtml.push('>');
+
+            for child in node.children() {
+                write_xhtml_node(child, xhtml);
+            }
+
+            xhtml.push_str(&format!("</{name}>"));
+        },
+        // Comments, doctypes & processing instructions are dropped.
+        _ => (),
+    }
+}
+
+/// Escape a string so it can be used in xml text or attribute values.
+///
+/// Characters that aren't allowed in xml are removed, and non-breaking spaces become normal spaces since they can cause certain e-readers to crash.
+fn escape_xml(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
 
-    // Write the html to the stdin, then wait for xhtml to be outputted to the stdout.
-    html2xhtml
-        .stdin
-        .as_mut()
-        .unwrap()
-        .write_all(html.as_bytes())
-        .unwrap();
-    let html2xhtml_output = html2xhtml.wait_with_output().unwrap();
+    for char in string.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\u{a0}' => escaped.push(' '),
+            '\t' | '\n' | '\r' => escaped.push(char),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => (),
+            _ => escaped.push(char),
+        }
+    }
+
+    escaped
+}
+
+/// Check if a string is a valid xml attribute or element name without a namespace prefix.
+fn is_valid_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_alphabetic() || first == '_' => {
+            chars.all(|char| char.is_alphanumeric() || matches!(char, '_' | '-' | '.'))
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_classes_from_display_none_rules() {
+        let html = string_to_html_document(
+            r#"<html><head><style>
+            .cmVhZGVy { display: none; speak: never; }
+            .shown { color: red; }
+            p.a1, span .b2{display:none}
+            </style></head><body><p class="cmVhZGVy">Stolen</p></body></html>"#,
+        );
+
+        assert_eq!(get_hidden_classes(&html), vec!["cmVhZGVy", "a1", "b2"]);
+    }
+
+    #[test]
+    fn hidden_classes_in_at_rules() {
+        let html = string_to_html_document(
+            r#"<html><head><style>
+            @import url("fonts.css");
+            /* .commented { display: none; } */
+            @media screen and (min-width: 100px) {
+                .mediaHidden { display: none; }
+                .mediaShown { display: block; }
+            }
+            @supports (display: grid) { div.supportsHidden { display : none } }
+            .after { display: none; }
+            </style></head><body></body></html>"#,
+        );
+
+        assert_eq!(
+            get_hidden_classes(&html),
+            vec!["mediaHidden", "supportsHidden", "after"]
+        );
+    }
+
+    #[test]
+    fn hidden_classes_without_styles() {
+        let html = string_to_html_document("<html><body><p>Nothing hidden</p></body></html>");
+        assert!(get_hidden_classes(&html).is_empty());
+    }
+
+    #[test]
+    fn html_to_text_paragraphs() {
+        let html = string_to_html_fragment(
+            "<p>First   paragraph\n spread over lines.</p><div>Second<br>line</div><script>alert(1)</script><img src=\"a.png\">",
+        );
+
+        assert_eq!(
+            html_to_text(&html),
+            "First paragraph spread over lines.\n\nSecond\n\nline"
+        );
+    }
+
+    #[test]
+    fn html_to_text_inline_punctuation() {
+        let html = string_to_html_fragment(
+            "<p><em>Really</em>? She said <q>no</q>, then <b>left</b>.</p><p>Hello <em>there</em> friend</p>",
+        );
+
+        assert_eq!(
+            html_to_text(&html),
+            "Really? She said no, then left.\n\nHello there friend"
+        );
+    }
 
-    // Generate a lossy string from the stdout.
-    let xhtml = String::from_utf8_lossy(&html2xhtml_output.stdout).to_string();
+    #[test]
+    fn html_to_text_words_split_across_elements() {
+        let html = string_to_html_fragment(
+            "<p><b>wo</b>rd <i>it</i><b>al</b>ic <span>\n  spaced </span> out</p>",
+        );
+
+        assert_eq!(html_to_text(&html), "word italic spaced out");
+
+        let html = string_to_html_fragment(
+            "<table>\n<tr>\n<td> <b>S</b>TR </td>\n<td>10</td>\n</tr>\n</table>",
+        );
+        assert_eq!(html_to_text(&html), "STR | 10");
+    }
+
+    #[test]
+    fn html_to_text_tables_and_scene_breaks() {
+        let html = string_to_html_fragment(
+            "<p>Before.</p><hr><table><tr><th>Stat</th><th>Value</th></tr><tr><td>STR</td><td>10</td></tr></table>",
+        );
+
+        assert_eq!(html_to_text(&html), "Before.\n\nStat | Value\nSTR | 10");
+        assert_eq!(
+            html_to_text_with_scene_breaks(&html, Some("* * *")),
+            "Before.\n\n* * *\n\nStat | Value\nSTR | 10"
+        );
+    }
+
+    #[test]
+    fn escape_xml_special_characters() {
+        assert_eq!(
+            escape_xml(r#"<a href="x">Tom & Jerry</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&lt;/a&gt;"
+        );
+        // Non-breaking spaces become spaces & characters xml doesn't allow are dropped.
+        assert_eq!(escape_xml("a\u{a0}b\u{0}\u{8}c\u{ffff}"), "a bc");
+        assert_eq!(escape_xml("line\tone\nline two\r"), "line\tone\nline two\r");
+    }
+
+    #[test]
+    fn html_to_xhtml_makes_well_formed_xhtml() {
+        let html = string_to_html_fragment(
+            r#"<p>One<br>Two &amp; three</p><img src="a.png" alt="A"><hr><script>alert(1)</script><!-- comment -->"#,
+        );
+        let xhtml = html_to_xhtml(&html, "Chapter <1>");
 
-    return Ok(xhtml);
+        assert!(xhtml.contains("<title>Chapter &lt;1&gt;</title>"));
+        assert!(xhtml.contains(r#"<p>One<br/>Two &amp; three</p><img src="a.png" alt="A"/><hr/>"#));
+        assert!(!xhtml.contains("script"));
+        assert!(!xhtml.contains("comment"));
+    }
+
+    #[test]
+    fn html_to_xhtml_drops_invalid_attributes() {
+        let html = string_to_html_fragment(r#"<p a<b="1" class="x" data-n="&quot;">Text</p>"#);
+        let xhtml = html_to_xhtml(&html, "Title");
+
+        assert!(xhtml.contains(r#"<p class="x" data-n="&quot;">Text</p>"#));
+    }
+
+    #[test]
+    fn html_to_xhtml_cleans_word_pastes() {
+        let html = string_to_html_fragment(
+            r#"<p class="MsoNormal" style="margin:0" o:spid="_x0000_s1026" xml:lang="en">Pasted<o:p></o:p></p><v:shape id="s1" o:spt="75"><v:imagedata src="a.png"></v:imagedata>Shape text</v:shape>"#,
+        );
+        let xhtml = html_to_xhtml(&html, "Title");
+
+        assert!(xhtml.contains(r#"<p class="MsoNormal" style="margin:0">Pasted</p>Shape text"#));
+        assert!(!xhtml.contains("o:"));
+        assert!(!xhtml.contains("v:"));
+        assert!(!xhtml.contains("xml:lang"));
+    }
+
+    #[test]
+    fn replace_images_in_document_order() {
+        let html = string_to_html_fragment(
+            r#"<p><img src="https://example.com/a.png"> <img src="https://example.com/b.png" alt="B"></p>"#,
+        );
+        let a_url: Url = "https://example.com/a.png".parse().unwrap();
+
+        let replaced = replace_images(&html, |url| {
+            (*url == a_url).then(|| "image_a.png".to_string())
+        })
+        .root_element()
+        .inner_html();
+
+        assert!(
+            replaced.starts_with(r#"<p><img src="image_a.png"> <div class="image-placeholder">"#)
+        );
+        assert!(replaced.contains("https://example.com/b.png"));
+        assert!(!replaced.contains(r#"<img src="https://example.com/b.png""#));
+    }
 }
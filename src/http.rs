@@ -1,57 +1,472 @@
-use std::collections::HashMap;
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
 
-use reqwest::{blocking::Response, header::HeaderMap};
+use bytes::{Buf, Bytes};
+use chrono::{DateTime, Local, Utc};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderValue, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, RETRY_AFTER,
+    },
+    StatusCode,
+};
+use sha2::{Digest, Sha256};
 use url::Url;
 
-use crate::{GenerationError, Warning, WARNINGS};
+use crate::{
+    cache::{Cache, CacheEntryMetadata},
+    constants, GenerationError, Warning, WARNINGS,
+};
+
+/// A struct controlling how politely requests are made to RoyalRoad.
+#[derive(Clone, Debug)]
+pub struct RequestPolicy {
+    /// The minimum delay between two requests.
+    pub request_delay: Duration,
+
+    /// How many times a failed request is retried before giving up.
+    pub max_retries: u32,
+
+    /// The delay before the first retry. Doubles with every retry after that.
+    pub retry_backoff: Duration,
+
+    /// How long to wait for a response before the request counts as failed.
+    pub timeout: Duration,
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        RequestPolicy {
+            request_delay: Duration::from_millis(constants::DEFAULT_REQUEST_DELAY),
+            max_retries: constants::DEFAULT_MAX_RETRIES,
+            retry_backoff: Duration::from_millis(constants::DEFAULT_RETRY_BACKOFF),
+            timeout: Duration::from_secs(constants::DEFAULT_TIMEOUT),
+        }
+    }
+}
+
+/// A trait for anything that can fetch http responses.
+///
+/// Every request made while archiving a book goes through a fetcher,
+/// so the whole pipeline can run against something other than RoyalRoad.
+pub trait Fetcher: Sync {
+    /// Fetch the response for a url. A cached copy may be returned.
+    fn fetch(&self, url: &Url) -> Result<HttpResponse, GenerationError>;
+
+    /// Fetch the response for a url, making sure any cached copy is still up to date.
+    fn fetch_fresh(&self, url: &Url) -> Result<HttpResponse, GenerationError> {
+        self.fetch(url)
+    }
+}
+
+/// A fetcher that makes real http requests using reqwest, following the request policy & using the cache.
+///
+/// Everything a request needs is kept in the fetcher, so several fetchers can be used at the same time
+/// without getting in each other's way.
+pub struct ReqwestFetcher {
+    /// The client used for all requests, built to follow the request policy.
+    client: reqwest::blocking::Client,
+
+    /// The policy used for all requests.
+    request_policy: RequestPolicy,
+
+    /// The cache used for all requests. None if caching is disabled.
+    cache: Option<Cache>,
+
+    /// The earliest time the next request is allowed to be made.
+    /// Shared by every request so the delay between requests holds no matter which thread makes them.
+    next_request_time: Mutex<Instant>,
+}
+
+impl ReqwestFetcher {
+    /// Create a fetcher that follows the given policy & uses the given cache, if any.
+    pub fn new(request_policy: RequestPolicy, cache: Option<Cache>) -> Self {
+        ReqwestFetcher {
+            client: build_client(&request_policy),
+            request_policy,
+            cache,
+            next_request_time: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Default for ReqwestFetcher {
+    fn default() -> Self {
+        ReqwestFetcher::new(RequestPolicy::default(), None)
+    }
+}
+
+impl Fetcher for ReqwestFetcher {
+    /// If the url is cached the cached response is returned without touching the network.
+    fn fetch(&self, url: &Url) -> Result<HttpResponse, GenerationError> {
+        self.get_cached_response(url.clone(), false)
+    }
+
+    /// If the url is cached the server is asked whether the cached response is still up to date,
+    /// and the cached response is only used if it is.
+    fn fetch_fresh(&self, url: &Url) -> Result<HttpResponse, GenerationError> {
+        self.get_cached_response(url.clone(), true)
+    }
+}
+
+/// A fetcher that serves saved pages from a directory instead of the network.
+///
+/// A url is looked up as ``<directory>/<host>/<path>``, then with ``.html`` appended,
+/// then as ``<directory>/<host>/<path>/index.html``. The content-type is guessed from the file extension.
+/// E.G. ``https://www.royalroad.com/fiction/59450/bioshifter`` can be saved as
+/// ``<directory>/www.royalroad.com/fiction/59450/bioshifter.html``.
+///
+/// A query is part of the file name, like wget saves it, so urls that only differ by their query don't get mixed up.
+/// E.G. ``https://www.royalroadcdn.com/cover.jpg?time=1`` is looked up as ``cover.jpg?time=1``,
+/// or ``cover.jpg@time=1`` since windows doesn't allow a '?' in file names.
+pub struct FixtureFetcher {
+    pub directory: PathBuf,
+}
+
+impl Fetcher for FixtureFetcher {
+    fn fetch(&self, url: &Url) -> Result<HttpResponse, GenerationError> {
+        let base_path = self
+            .directory
+            .join(url.host_str().unwrap_or_default())
+            .join(url.path().trim_start_matches('/'));
+
+        let queries = match url.query() {
+            Some(query) => vec![format!("?{query}"), format!("@{query}")],
+            None => vec![String::new()],
+        };
+
+        for query in queries {
+            let candidate_paths = [
+                PathBuf::from(format!("{}{query}", base_path.to_string_lossy())),
+                PathBuf::from(format!("{}{query}.html", base_path.to_string_lossy())),
+                PathBuf::from(format!(
+                    "{}{query}",
+                    base_path.join("index.html").to_string_lossy()
+                )),
+            ];
+
+            for path in candidate_paths {
+                if path.is_file() {
+                    // The query would get in the way of guessing the content-type from the extension.
+                    let type_path = PathBuf::from(path.to_string_lossy().replacen(&query, "", 1));
+                    return read_fixture(url, path, &type_path);
+                }
+            }
+        }
+
+        Err(GenerationError::FixtureNotFoundError { url: url.clone() })
+    }
+}
+
+/// Read a saved page into a response for the given url, guessing its content-type from the extension of the type path.
+fn read_fixture(
+    url: &Url,
+    path: PathBuf,
+    type_path: &Path,
+) -> Result<HttpResponse, GenerationError> {
+    let body = match fs::read(&path) {
+        Ok(body) => body,
+        Err(error) => {
+            return Err(GenerationError::FixtureReadError {
+                error,
+                file_path: path,
+            })
+        },
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(guess_content_type(type_path)),
+    );
+
+    Ok(HttpResponse::new(
+        url.clone(),
+        StatusCode::OK,
+        headers,
+        Bytes::from(body),
+    ))
+}
+
+/// Get the normalized content(mime)-type & file extension for an image content-type header.
+///
+/// Returns None if it's not an image type we know.
+fn image_type_from_content_type(content_type: &str) -> Option<(&'static str, &'static str)> {
+    // Drop any parameters, E.G. ``image/jpeg; charset=binary``
+    let content_type = content_type.split(';').next().unwrap_or_default();
+
+    match content_type.trim().to_lowercase().as_str() {
+        "image/png" => Some(("image/png", "png")),
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(("image/jpeg", "jpeg")),
+        "image/gif" => Some(("image/gif", "gif")),
+        "image/webp" => Some(("image/webp", "webp")),
+        "image/svg+xml" => Some(("image/svg+xml", "svg")),
+        "image/avif" => Some(("image/avif", "avif")),
+        "image/bmp" | "image/x-bmp" | "image/x-ms-bmp" => Some(("image/bmp", "bmp")),
+        _ => None,
+    }
+}
+
+/// Work out the content(mime)-type & file extension of an image from its first bytes.
+///
+/// Returns None if it doesn't look like an image type we know.
+fn sniff_image_type(head: &[u8]) -> Option<(&'static str, &'static str)> {
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("image/png", "png"))
+    } else if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(("image/jpeg", "jpeg"))
+    } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        Some(("image/gif", "gif"))
+    } else if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        Some(("image/webp", "webp"))
+    } else if head.get(4..8) == Some(b"ftyp") && matches!(head.get(8..12), Some(b"avif" | b"avis"))
+    {
+        Some(("image/avif", "avif"))
+    } else if head.starts_with(b"BM") && head.len() >= 14 {
+        Some(("image/bmp", "bmp"))
+    } else if is_svg(head) {
+        Some(("image/svg+xml", "svg"))
+    } else {
+        None
+    }
+}
+
+/// Whether the first bytes of a file look like an svg image, meaning its root element is ``<svg>``.
+///
+/// The xml declaration, doctype & comments before the root element are skipped,
+/// so html pages that merely contain an inline svg don't count.
+fn is_svg(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let mut head = head.trim_start_matches('\u{FEFF}').trim_start();
+
+    loop {
+        let end = if head.starts_with("<?") {
+            "?>"
+        } else if head.starts_with("<!--") {
+            "-->"
+        } else if head.starts_with("<!") {
+            ">"
+        } else {
+            break;
+        };
+
+        // Give up if the prolog doesn't end within the head.
+        match head.find(end) {
+            Some(index) => head = head[index + end.len()..].trim_start(),
+            None => return false,
+        }
+    }
+
+    head.strip_prefix("<svg").is_some_and(|rest| {
+        rest.starts_with(|char: char| char.is_whitespace() || char == '>' || char == '/')
+    })
+}
+
+/// Guess the content-type of a file from its extension.
+fn guess_content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "" | "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
 
 // A struct representing an HttpResponse and the Url it originated from.
+#[derive(Clone)]
 pub struct HttpResponse {
     url: Url,
-    pub response: Response,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: ResponseBody,
+}
+
+/// The body of a response.
+///
+/// Cached bodies are left on disk until they are needed, so big images don't have to be held in memory.
+#[derive(Clone)]
+enum ResponseBody {
+    Memory(Bytes),
+    File(PathBuf),
 }
 
 impl HttpResponse {
+    /// Build a response from a body held in memory, E.G. for a custom fetcher.
+    pub fn new(url: Url, status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+        HttpResponse {
+            url,
+            status,
+            headers,
+            body: ResponseBody::Memory(body),
+        }
+    }
+
+    /// Build a response from a cache entry. Returns None if the cached body can not be read.
+    ///
+    /// The body is only read from disk when it's used.
+    fn from_cache(url: Url, metadata: &CacheEntryMetadata, body_path: &Path) -> Option<Self> {
+        if !body_path.is_file() {
+            return None;
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = &metadata.content_type {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).ok()?);
+        }
+        // Keep the validators, so whoever uses the response can tell if it changed.
+        if let Some(etag) = metadata.etag.as_ref().and_then(|etag| etag.parse().ok()) {
+            headers.insert(ETAG, etag);
+        }
+        if let Some(last_modified) = metadata
+            .last_modified
+            .as_ref()
+            .and_then(|last_modified| last_modified.parse().ok())
+        {
+            headers.insert(LAST_MODIFIED, last_modified);
+        }
+
+        Some(HttpResponse {
+            url,
+            status: StatusCode::OK,
+            headers,
+            body: ResponseBody::File(body_path.to_path_buf()),
+        })
+    }
+
     /// Get the response headers.
     pub fn get_headers(&self) -> &HeaderMap {
-        self.response.headers()
+        &self.headers
+    }
+
+    /// Convert the response to text. Invalid utf-8 gets replaced.
+    pub fn get_text(self) -> String {
+        String::from_utf8_lossy(&self.read_body()).into_owned()
     }
 
-    /// Attempt to convert the response to text. Exits the program if it fails.
-    pub fn get_text(self) -> Result<String, GenerationError> {
-        match self.response.text() {
-            Ok(response_text) => Ok(response_text),
-            Err(error) => Err(GenerationError::ResponseConvertToTextError { error }),
+    /// Convert the response to bytes. Used for images.
+    pub fn get_bytes(&self) -> bytes::Bytes {
+        self.read_body()
+    }
+
+    /// Get a reader over the response body, streaming it from disk if it's cached.
+    pub fn into_reader(self) -> Result<Box<dyn Read>, std::io::Error> {
+        match self.body {
+            ResponseBody::Memory(body) => Ok(Box::new(body.reader())),
+            ResponseBody::File(body_path) => Ok(Box::new(BufReader::new(File::open(body_path)?))),
         }
     }
 
-    /// Attempt to convert the response to bytes. Used for images. Exits the program if it fails.
-    pub fn get_bytes(self) -> Result<bytes::Bytes, GenerationError> {
-        match self.response.bytes() {
-            Ok(response_bytes) => Ok(response_bytes),
-            Err(error) => Err(GenerationError::ResponseConvertToBytesError { error }),
+    /// Get the sha256 hash of the response body, used to find identical images served from different urls.
+    pub fn content_hash(&self) -> String {
+        format!("{:x}", Sha256::digest(self.read_body()))
+    }
+
+    /// Read up to the first ``length`` bytes of the body, without reading the rest of a cached body.
+    fn read_head(&self, length: usize) -> Vec<u8> {
+        match &self.body {
+            ResponseBody::Memory(body) => body[..body.len().min(length)].to_vec(),
+            ResponseBody::File(body_path) => {
+                let mut head = Vec::with_capacity(length);
+                if let Ok(file) = File::open(body_path) {
+                    // A failed read just means the type can't be sniffed.
+                    let _ = file.take(length as u64).read_to_end(&mut head);
+                }
+                head
+            },
         }
     }
 
-    /// Attempt to get the content(mime)-type and file extension from the http-header.
+    /// Read the whole body into memory.
     ///
-    /// If the content-type header value can not be found it will warn the use and return empty strings.
+    /// If a cached body can't be read a warning is added and the body is empty.
+    fn read_body(&self) -> Bytes {
+        let body_path = match &self.body {
+            ResponseBody::Memory(body) => return body.clone(),
+            ResponseBody::File(body_path) => body_path,
+        };
+
+        match fs::read(body_path) {
+            Ok(body) => Bytes::from(body),
+            Err(error) => {
+                let warning = Warning::CacheReadError {
+                    warning_msg: "Unable to read from the cache".to_string(),
+                    url: self.url.clone(),
+                    error: error.to_string(),
+                };
+                WARNINGS.lock().unwrap().add_warning(warning);
+
+                Bytes::new()
+            },
+        }
+    }
+
+    /// Get the ``etag`` header of the response, if it has one.
+    pub fn get_etag(&self) -> Option<String> {
+        self.get_header_string(ETAG.as_str())
+    }
+
+    /// Get the ``last-modified`` header of the response, if it has one.
+    pub fn get_last_modified(&self) -> Option<String> {
+        self.get_header_string(LAST_MODIFIED.as_str())
+    }
+
+    /// Get the value of a header as a string, if it exists.
+    fn get_header_string(&self, header: &str) -> Option<String> {
+        self.headers
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    }
+
+    /// Build the cache metadata describing this response.
+    fn cache_metadata(&self) -> CacheEntryMetadata {
+        CacheEntryMetadata {
+            url: self.url.clone(),
+            fetched_at: Local::now(),
+            content_type: self.get_header_string(CONTENT_TYPE.as_str()),
+            etag: self.get_etag(),
+            last_modified: self.get_last_modified(),
+        }
+    }
+
+    /// Attempt to get the content(mime)-type and file extension of an image.
+    ///
+    /// The type is sniffed from the first bytes of the body, since some hosts send a missing or wrong content-type header.
+    /// If that doesn't work the content-type header is used instead.
+    /// If the content-type header value can not be found either it will warn the user and return empty strings.
     pub fn get_content_type_and_file_extension(&self) -> (String, String) {
-        // A hashmap to convert mime-types to file extensions.
-        let mime_to_file_extension: HashMap<&str, &str> = HashMap::from([
-            ("image/png", "png"),
-            ("image/webp", "webp"),
-            ("image/jpeg", "jpeg"),
-            ("image/jpg", "jpg"),
-        ]);
-
-        let content_type = match self.get_headers()["content-type"].to_str() {
-            Ok(content_type) => content_type,
-            Err(warning) => {
+        if let Some((content_type, file_extension)) = sniff_image_type(&self.read_head(1024)) {
+            return (content_type.to_string(), file_extension.to_string());
+        }
+
+        let content_type = match self
+            .get_headers()
+            .get(CONTENT_TYPE)
+            .map(HeaderValue::to_str)
+        {
+            Some(Ok(content_type)) => content_type,
+            content_type => {
                 let warning = Warning::MissingContentType {
                     warning_msg: "Unable to find or parse the content-type header".to_string(),
                     url: self.url.clone(),
-                    error: warning,
+                    error: content_type.and_then(Result::err),
                 };
                 WARNINGS.lock().unwrap().add_warning(warning);
 
@@ -59,25 +474,203 @@ impl HttpResponse {
             },
         };
 
-        if mime_to_file_extension.contains_key(content_type) {
-            return (
-                content_type.to_string(),
-                mime_to_file_extension[content_type].to_string(),
-            );
-        } else {
-            return (content_type.to_string(), String::with_capacity(0));
+        match image_type_from_content_type(content_type) {
+            Some((content_type, file_extension)) => {
+                (content_type.to_string(), file_extension.to_string())
+            },
+            // Unknown types still get an extension, so files don't end up named like ``image_3.``
+            None => (content_type.to_string(), "bin".to_string()),
         }
     }
 }
 
-/// Get an http response for a given url. Exits the program if it fails.
-pub fn get_response(url: Url) -> Result<HttpResponse, GenerationError> {
-    let response_result = reqwest::blocking::get(url.clone());
+/// Build an http client that follows the given policy.
+fn build_client(request_policy: &RequestPolicy) -> reqwest::blocking::Client {
+    reqwest::blocking::Client::builder()
+        .timeout(request_policy.timeout)
+        .build()
+        .unwrap()
+}
+
+impl ReqwestFetcher {
+    /// Get an http response for a given url, using and filling the cache if there is one.
+    fn get_cached_response(
+        &self,
+        url: Url,
+        revalidate: bool,
+    ) -> Result<HttpResponse, GenerationError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.send(url, HeaderMap::new()),
+        };
+
+        let mut conditional_headers = HeaderMap::new();
+        let cached_entry = cache.get(&url);
+
+        if let Some((metadata, body_path)) = &cached_entry {
+            if !revalidate {
+                if let Some(response) = HttpResponse::from_cache(url.clone(), metadata, body_path) {
+                    return Ok(response);
+                }
+            }
+
+            // Use the validators recorded with the entry to ask the server if it has changed.
+            if let Some(etag) = metadata.etag.as_ref().and_then(|etag| etag.parse().ok()) {
+                conditional_headers.insert(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = metadata
+                .last_modified
+                .as_ref()
+                .and_then(|last_modified| last_modified.parse().ok())
+            {
+                conditional_headers.insert(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = self.send(url.clone(), conditional_headers)?;
+
+        if response.status == StatusCode::NOT_MODIFIED {
+            if let Some((mut metadata, body_path)) = cached_entry {
+                if let Some(cached_response) =
+                    HttpResponse::from_cache(url.clone(), &metadata, &body_path)
+                {
+                    metadata.fetched_at = Local::now();
+                    cache.update_metadata(&metadata);
 
-    match response_result {
-        Ok(response) => Ok(HttpResponse { url, response }),
-        Err(error) => return Err(GenerationError::ResponseGetError { error, url }),
+                    return Ok(cached_response);
+                }
+            }
+
+            // The cached body vanished since it was looked up, so fetch the whole thing again.
+            let response = self.send(url, HeaderMap::new())?;
+            if response.status.is_success() {
+                cache.store(&response.cache_metadata(), &response.read_body());
+            }
+            return Ok(response);
+        }
+
+        if response.status.is_success() {
+            cache.store(&response.cache_metadata(), &response.read_body());
+        }
+
+        Ok(response)
     }
+
+    /// Make an http request for the given url with the given extra headers & read the whole response.
+    ///
+    /// Follows the request policy: requests are spaced out by the request delay, and failed requests
+    /// are retried with exponential backoff. ``Retry-After`` headers are honoured on 429 & 503 responses.
+    fn send(&self, url: Url, headers: HeaderMap) -> Result<HttpResponse, GenerationError> {
+        let request_policy = &self.request_policy;
+
+        let mut attempt: u32 = 0;
+        loop {
+            self.wait_for_turn();
+
+            let result = self
+                .client
+                .get(url.clone())
+                .headers(headers.clone())
+                .send()
+                .and_then(|response| {
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    response.bytes().map(|body| (status, headers, body))
+                });
+
+            let retries_left = attempt < request_policy.max_retries;
+
+            match result {
+                Ok((status, headers, body))
+                    if status.is_success() || status == StatusCode::NOT_MODIFIED =>
+                {
+                    return Ok(HttpResponse::new(url, status, headers, body));
+                },
+                Ok((status, headers, _)) if retries_left && is_retryable(status) => {
+                    // Let every request wait for as long as the server asked, not just this one.
+                    if let Some(retry_after) = get_retry_after(status, &headers) {
+                        self.delay_next_request(retry_after);
+                    } else {
+                        thread::sleep(get_backoff(request_policy, attempt));
+                    }
+                },
+                Ok((status, _, _)) => {
+                    return Err(GenerationError::ResponseStatusError { status, url });
+                },
+                Err(_) if retries_left => {
+                    thread::sleep(get_backoff(request_policy, attempt));
+                },
+                Err(error) => {
+                    return Err(GenerationError::ResponseGetError { error, url });
+                },
+            }
+
+            attempt += 1;
+        }
+    }
+
+    /// Wait until the request delay since the last request has passed & claim the next slot.
+    fn wait_for_turn(&self) {
+        let now = Instant::now();
+        let request_time = {
+            let mut next_request_time = self.next_request_time.lock().unwrap();
+            let request_time = (*next_request_time).max(now);
+            *next_request_time = request_time + self.request_policy.request_delay;
+            request_time
+        };
+
+        thread::sleep(request_time.saturating_duration_since(now));
+    }
+
+    /// Push back the time the next request is allowed to be made by the given delay.
+    fn delay_next_request(&self, delay: Duration) {
+        let mut next_request_time = self.next_request_time.lock().unwrap();
+        *next_request_time = (*next_request_time).max(Instant::now() + delay);
+    }
+}
+
+/// Whether a request that got a response with this status code is worth trying again.
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+/// Get how long the server asked us to wait for 429 & 503 responses.
+///
+/// The ``Retry-After`` header is either a number of seconds or an http date.
+/// The wait is capped at the maximum backoff, so a server can't stall the run for hours.
+fn get_retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+
+    let retry_after = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    let retry_after = match retry_after.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let retry_time = DateTime::parse_from_rfc2822(retry_after).ok()?;
+            (retry_time.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .ok()?
+        },
+    };
+
+    Some(retry_after.min(Duration::from_secs(constants::MAX_RETRY_BACKOFF)))
+}
+
+/// Get how long to wait before a retry.
+///
+/// The backoff doubles with every attempt up to a maximum, and is randomly shortened by up to half
+/// so that many requests failing at once don't all retry at the same moment.
+fn get_backoff(request_policy: &RequestPolicy, attempt: u32) -> Duration {
+    let backoff = request_policy
+        .retry_backoff
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(Duration::from_secs(constants::MAX_RETRY_BACKOFF));
+
+    backoff.mul_f64(1.0 - fastrand::f64() / 2.0)
 }
 
 /// A function to convert a string to a url. Exits the program if it fails.
@@ -90,3 +683,122 @@ pub fn string_to_url(url: &str) -> Result<Url, GenerationError> {
         }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_known_image_types() {
+        assert_eq!(
+            sniff_image_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some(("image/png", "png"))
+        );
+        assert_eq!(
+            sniff_image_type(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10]),
+            Some(("image/jpeg", "jpeg"))
+        );
+        assert_eq!(
+            sniff_image_type(b"GIF89a\x01\0"),
+            Some(("image/gif", "gif"))
+        );
+        assert_eq!(
+            sniff_image_type(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(("image/webp", "webp"))
+        );
+        assert_eq!(
+            sniff_image_type(b"\0\0\0\x1cftypavif\0\0\0\0"),
+            Some(("image/avif", "avif"))
+        );
+        assert_eq!(
+            sniff_image_type(b"BM\x46\0\0\0\0\0\0\0\x36\0\0\0"),
+            Some(("image/bmp", "bmp"))
+        );
+    }
+
+    #[test]
+    fn sniff_svg() {
+        assert_eq!(
+            sniff_image_type(b"<svg xmlns=\"http://www.w3.org/2000/svg\"></svg>"),
+            Some(("image/svg+xml", "svg"))
+        );
+        assert_eq!(
+            sniff_image_type("\u{FEFF}  <?xml version=\"1.0\"?>\n<svg></svg>".as_bytes()),
+            Some(("image/svg+xml", "svg"))
+        );
+
+        assert_eq!(
+            sniff_image_type(
+                b"<?xml version=\"1.0\"?>\n<!-- Drawn by hand <svg> -->\n<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\">\n<svg\nwidth=\"1\"/>"
+            ),
+            Some(("image/svg+xml", "svg"))
+        );
+
+        // Xml that isn't an svg.
+        assert_eq!(
+            sniff_image_type(b"<?xml version=\"1.0\"?><rss></rss>"),
+            None
+        );
+        assert_eq!(sniff_image_type(b"<svgfont></svgfont>"), None);
+    }
+
+    #[test]
+    fn sniff_html_with_inline_svg() {
+        // Error pages with an svg icon in them aren't svg images.
+        assert_eq!(
+            sniff_image_type(
+                b"<!DOCTYPE html><html><body><svg viewBox=\"0 0 1 1\"></svg>Not found</body></html>"
+            ),
+            None
+        );
+        assert_eq!(
+            sniff_image_type(b"<!-- error --><html><svg></svg></html>"),
+            None
+        );
+    }
+
+    #[test]
+    fn sniff_unknown_types() {
+        assert_eq!(sniff_image_type(b""), None);
+        assert_eq!(sniff_image_type(b"<!DOCTYPE html><html></html>"), None);
+        // Too short to be a bmp.
+        assert_eq!(sniff_image_type(b"BM"), None);
+        // An iso media file that isn't an avif.
+        assert_eq!(sniff_image_type(b"\0\0\0\x18ftypmp42\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn fixture_fetcher_keeps_the_query() {
+        let directory = std::env::temp_dir().join(format!(
+            "royal_road_archiver_fixtures_{}",
+            std::process::id()
+        ));
+        let host_directory = directory.join("img.example.com");
+        fs::create_dir_all(&host_directory).unwrap();
+        fs::write(host_directory.join("cover.png"), "no query").unwrap();
+        fs::write(host_directory.join("cover.png?time=1"), "time 1").unwrap();
+        fs::write(host_directory.join("cover.png@time=2"), "time 2").unwrap();
+
+        let fetcher = FixtureFetcher {
+            directory: directory.clone(),
+        };
+        let fetch = |url: &str| fetcher.fetch(&url.parse().unwrap());
+
+        let response = fetch("https://img.example.com/cover.png?time=1").unwrap();
+        assert_eq!(&response.get_bytes()[..], b"time 1");
+        assert_eq!(
+            response.get_content_type_and_file_extension().0,
+            "image/png"
+        );
+
+        let response = fetch("https://img.example.com/cover.png?time=2").unwrap();
+        assert_eq!(&response.get_bytes()[..], b"time 2");
+
+        let response = fetch("https://img.example.com/cover.png").unwrap();
+        assert_eq!(&response.get_bytes()[..], b"no query");
+
+        assert!(fetch("https://img.example.com/cover.png?time=3").is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
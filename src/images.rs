@@ -0,0 +1,164 @@
+use std::io::Cursor;
+
+use clap::Args;
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, GenericImageView, ImageError,
+    ImageFormat, Rgb, RgbImage,
+};
+
+/// The jpeg quality used when an image has to be re-encoded as a jpeg & no quality was given.
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+/// The image types the epub spec says every reader has to be able to show.
+const EPUB_IMAGE_TYPES: [&str; 5] = [
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/svg+xml",
+    "image/webp",
+];
+
+/// struct that corresponds to arguments for shrinking the images in a book, E.G. for e-readers.
+///
+/// Images are left untouched unless one of the options is given.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ImageArgs {
+    /// Shrink images wider than this many pixels, keeping their aspect ratio.
+    #[arg(long)]
+    pub max_image_width: Option<u32>,
+
+    /// Shrink images taller than this many pixels, keeping their aspect ratio.
+    #[arg(long)]
+    pub max_image_height: Option<u32>,
+
+    /// Convert images to grayscale. Useful for e-ink readers.
+    #[arg(long)]
+    pub grayscale: bool,
+
+    /// Recompress jpeg & webp images at this quality (1-100).
+    /// Webp images get converted to jpeg to do this, since there's no lossy webp encoder available.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub image_quality: Option<u8>,
+
+    /// Convert webp images to jpeg, for older readers that can't show webp.
+    #[arg(long)]
+    pub webp_to_jpeg: bool,
+}
+
+impl ImageArgs {
+    /// Whether any of the options have been given, meaning the images need processing.
+    pub fn is_enabled(&self) -> bool {
+        self.max_image_width.is_some()
+            || self.max_image_height.is_some()
+            || self.grayscale
+            || self.image_quality.is_some()
+            || self.webp_to_jpeg
+    }
+
+    /// Whether webp images end up as jpegs.
+    fn converts_webp(&self) -> bool {
+        self.webp_to_jpeg || self.image_quality.is_some()
+    }
+
+    /// Get the content(mime)-type & file extension an image has after being converted by processing.
+    pub fn output_content_type_and_file_extension(
+        &self,
+        content_type: String,
+        file_extension: String,
+    ) -> (String, String) {
+        if content_type == "image/webp" && self.converts_webp() {
+            ("image/jpeg".to_string(), "jpeg".to_string())
+        } else if needs_conversion(&content_type) {
+            ("image/png".to_string(), "png".to_string())
+        } else {
+            (content_type, file_extension)
+        }
+    }
+}
+
+/// Whether an image has to be converted to another format to go in an epub, even without any image args.
+///
+/// Only bmps can be converted. Avifs would need a native decoder, so they get a placeholder instead.
+pub fn needs_conversion(content_type: &str) -> bool {
+    content_type == "image/bmp"
+}
+
+/// Whether every epub reader should be able to show this type of image.
+pub fn is_epub_image_type(content_type: &str) -> bool {
+    EPUB_IMAGE_TYPES.contains(&content_type)
+}
+
+/// Resize, grayscale & recompress an image according to the image args. Bmps are always converted to png.
+///
+/// Returns the image along with whether it was converted to another format,
+/// in which case its type is the one given by ``output_content_type_and_file_extension``.
+/// Gifs (which might be animated) & formats that can't be decoded are returned unchanged.
+/// If recompressing an image without changing it makes it bigger the original is kept.
+pub fn process_image(
+    image_bytes: &[u8],
+    image_args: &ImageArgs,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let input_format = match image::guess_format(image_bytes) {
+        Ok(
+            format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Bmp),
+        ) => format,
+        _ => return Ok((image_bytes.to_vec(), false)),
+    };
+
+    let output_format = match input_format {
+        ImageFormat::WebP if image_args.converts_webp() => ImageFormat::Jpeg,
+        ImageFormat::Bmp => ImageFormat::Png,
+        format => format,
+    };
+
+    let mut image = image::load_from_memory_with_format(image_bytes, input_format)?;
+
+    let (width, height) = image.dimensions();
+    let max_width = image_args.max_image_width.unwrap_or(u32::MAX);
+    let max_height = image_args.max_image_height.unwrap_or(u32::MAX);
+    let resized = width > max_width || height > max_height;
+    if resized {
+        image = image.resize(max_width, max_height, FilterType::CatmullRom);
+    }
+
+    // Jpegs have no transparency, so put anything transparent on a white background instead of letting it go black.
+    if output_format == ImageFormat::Jpeg && image.color().has_alpha() {
+        image = DynamicImage::ImageRgb8(flatten_onto_white(&image));
+    }
+
+    if image_args.grayscale {
+        image = image.grayscale();
+    }
+
+    let mut processed_bytes: Vec<u8> = Vec::new();
+    match output_format {
+        ImageFormat::Jpeg => {
+            let quality = image_args.image_quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+            image
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut processed_bytes, quality))?;
+        },
+        format => image.write_to(&mut Cursor::new(&mut processed_bytes), format)?,
+    }
+
+    // Only recompressing can make an image bigger, in which case there's no point to it.
+    let only_recompressed = output_format == input_format && !resized && !image_args.grayscale;
+    if only_recompressed && processed_bytes.len() >= image_bytes.len() {
+        Ok((image_bytes.to_vec(), false))
+    } else {
+        Ok((processed_bytes, output_format != input_format))
+    }
+}
+
+/// Blend an image with transparency onto a white background.
+fn flatten_onto_white(image: &DynamicImage) -> RgbImage {
+    let rgba_image = image.to_rgba8();
+
+    RgbImage::from_fn(rgba_image.width(), rgba_image.height(), |x, y| {
+        let [red, green, blue, alpha] = rgba_image.get_pixel(x, y).0;
+        let blend = |channel: u8| -> u8 {
+            ((channel as u16 * alpha as u16 + 255 * (255 - alpha as u16)) / 255) as u8
+        };
+
+        Rgb([blend(red), blend(green), blend(blue)])
+    })
+}
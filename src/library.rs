@@ -1,31 +1,109 @@
 use std::{
-    collections::HashMap,
-    fs::OpenOptions,
-    io::Write,
-    path::PathBuf,
-    process::exit,
+    collections::{hash_map::Entry, HashMap},
+    fs::{self, OpenOptions},
+    io::{self, BufWriter, Cursor, Seek, Write},
+    path::{Path, PathBuf},
     sync::{Mutex, MutexGuard},
+    time::Duration,
 };
 
-use bytes::Buf;
+use base64::Engine;
 use chrono::prelude::Local;
-use clap::Args;
+use clap::{Args, ValueEnum};
 use epub_builder::{EpubBuilder, EpubContent, ReferenceType, ZipLibrary};
 use file_system_crap::convert_path_to_os_specific;
 use html::{html_to_xhtml, remove_image_tags, string_to_html_fragment};
 use indicatif::{ProgressBar, ProgressStyle};
 use lazy_static::lazy_static;
-use misc::Oses;
+use record::BookRecord;
 use reqwest::header::ToStrError;
+use run_state::RunState;
+use scraper::Html;
 use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
 use url::Url;
 
+pub use cache::Cache;
+pub use http::{Fetcher, FixtureFetcher, HttpResponse, RequestPolicy, ReqwestFetcher};
+pub use images::ImageArgs;
+pub use selection::{ChapterRange, ChapterSelection};
+pub use tts::{EspeakNg, Piper, TtsBackend};
+
 mod book;
+mod cache;
 mod constants;
 mod file_system_crap;
 mod html;
 mod http;
+mod images;
 mod misc;
+mod pdf;
+mod record;
+mod run_state;
+mod selection;
+mod tts;
+
+/// struct that corresponds to arguments shared by all generation modes that control how the book is downloaded.
+#[derive(Args, Debug)]
+pub struct DownloadArgs {
+    /// Cache downloaded chapter pages & images in this directory.
+    /// Later runs, or generating another format, read from the cache instead of downloading them again.
+    #[arg(long, global = true)]
+    pub cache_directory: Option<PathBuf>,
+
+    /// Resume an interrupted run, reusing the chapters & images it already downloaded instead of starting over.
+    #[arg(long, global = true)]
+    pub resume: bool,
+
+    /// The minimum delay between requests in milliseconds.
+    #[arg(long, global = true, default_value_t = constants::DEFAULT_REQUEST_DELAY)]
+    pub request_delay: u64,
+
+    /// How many times a failed request is retried before giving up.
+    #[arg(long, global = true, default_value_t = constants::DEFAULT_MAX_RETRIES)]
+    pub max_retries: u32,
+
+    /// The delay before retrying a failed request in milliseconds. Doubles with every retry.
+    /// RoyalRoad asking us to wait longer always takes priority.
+    #[arg(long, global = true, default_value_t = constants::DEFAULT_RETRY_BACKOFF)]
+    pub retry_backoff: u64,
+
+    /// How long to wait for a response before a request counts as failed, in seconds.
+    #[arg(long, global = true, default_value_t = constants::DEFAULT_TIMEOUT)]
+    pub timeout: u64,
+
+    /// How many chapters or images to download at the same time.
+    /// Requests are still spaced out by the request delay.
+    #[arg(short, long, global = true, default_value_t = constants::DEFAULT_JOBS)]
+    pub jobs: usize,
+
+    /// Serve every request from pages saved in this directory instead of RoyalRoad.
+    /// Pages are looked up as <directory>/<host>/<path>, <directory>/<host>/<path>.html or <directory>/<host>/<path>/index.html.
+    /// A query stays part of the file name, E.G. <path>?<query>, or <path>@<query> on windows.
+    /// Nothing gets cached, so the cache directory is ignored.
+    /// Useful for testing & offline sandboxes.
+    #[arg(long, global = true)]
+    pub fixture_directory: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub chapter_selection: ChapterSelection,
+}
+
+impl Default for DownloadArgs {
+    fn default() -> Self {
+        DownloadArgs {
+            cache_directory: None,
+            resume: false,
+            request_delay: constants::DEFAULT_REQUEST_DELAY,
+            max_retries: constants::DEFAULT_MAX_RETRIES,
+            retry_backoff: constants::DEFAULT_RETRY_BACKOFF,
+            timeout: constants::DEFAULT_TIMEOUT,
+            jobs: constants::DEFAULT_JOBS,
+            fixture_directory: None,
+            chapter_selection: ChapterSelection::default(),
+        }
+    }
+}
 
 /// struct that corresponds to arguments for Audiobook generation.
 #[derive(Args, Debug)]
@@ -37,6 +115,45 @@ pub struct AudiobookArgs {
     /// Split the novel into multiple audio files by chapter.
     #[arg(short, long)]
     pub split_novel_by_chapters: bool,
+
+    /// The text-to-speech engine to use. It must be installed locally and available on the PATH.
+    #[arg(short, long, value_enum, default_value_t = TtsEngine::EspeakNg)]
+    pub tts_engine: TtsEngine,
+
+    /// The voice to use with espeak-ng, E.G. en-us.
+    #[arg(short, long)]
+    pub voice: Option<String>,
+
+    /// The voice model (.onnx file) to use with piper. Required when using piper.
+    #[arg(short = 'm', long, required_if_eq("tts_engine", "piper"))]
+    pub piper_model: Option<PathBuf>,
+
+    /// Where to put the author's notes from before & after each chapter.
+    #[arg(short, long, value_enum, default_value_t = AuthorNotes::Inline)]
+    pub author_notes: AuthorNotes,
+}
+
+/// The text-to-speech engines that can be used for Audiobook generation.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum TtsEngine {
+    /// https://github.com/espeak-ng/espeak-ng
+    EspeakNg,
+
+    /// https://github.com/rhasspy/piper
+    Piper,
+}
+
+/// Where the author's notes of each chapter end up in the generated book.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthorNotes {
+    /// Leave the author's notes out.
+    Omit,
+
+    /// Put the author's notes right before & after the chapter they belong to.
+    Inline,
+
+    /// Collect all the author's notes in an appendix at the end of the book.
+    Appendix,
 }
 
 /// struct that corresponds to arguments for Epub generation.
@@ -46,11 +163,155 @@ pub struct EpubArgs {
     /// Will speed up epub generation and significantly decrease epub size.
     #[arg(short, long)]
     pub no_images: bool,
+
+    /// Rebuild an existing epub, only downloading chapters that are new or have changed since the last run.
+    #[arg(short, long)]
+    pub update: bool,
+
+    /// Show the date each chapter was published next to its title.
+    #[arg(short = 'd', long)]
+    pub chapter_dates: bool,
+
+    /// Where to put the author's notes from before & after each chapter.
+    #[arg(short, long, value_enum, default_value_t = AuthorNotes::Inline)]
+    pub author_notes: AuthorNotes,
+
+    #[command(flatten)]
+    pub image_args: ImageArgs,
 }
 
 /// struct that corresponds to arguments for Html generation.
 #[derive(Args, Debug)]
-pub struct HtmlArgs {}
+pub struct HtmlArgs {
+    /// Disable the inclusion of images.
+    /// Images will be left out of the archive instead of being downloaded.
+    #[arg(short, long)]
+    pub no_images: bool,
+
+    /// Keep one copy of every image in this directory & hard link them into the archive.
+    /// Share it between archives so images they have in common are only stored once.
+    #[arg(long)]
+    pub image_store: Option<PathBuf>,
+
+    /// Put the whole webnovel in a single html file, with the images & stylesheet embedded in it.
+    /// Easy to email or drop into a note-taking app.
+    #[arg(short, long, conflicts_with = "image_store")]
+    pub single_file: bool,
+
+    /// Where to put the author's notes from before & after each chapter.
+    #[arg(short, long, value_enum, default_value_t = AuthorNotes::Inline)]
+    pub author_notes: AuthorNotes,
+}
+
+/// struct that corresponds to arguments for plain text generation.
+#[derive(Args, Debug)]
+pub struct TxtArgs {
+    /// Disable the generation of chapter titles. Useful to avoid chapter titles appearing twice.
+    #[arg(short = 'c', long)]
+    pub no_chapter_titles: bool,
+
+    /// Rebuild an existing text file, only downloading chapters that are new or have changed since the last run.
+    #[arg(short, long)]
+    pub update: bool,
+
+    /// Show the date each chapter was published next to its title.
+    #[arg(short = 'd', long)]
+    pub chapter_dates: bool,
+
+    /// Where to put the author's notes from before & after each chapter.
+    #[arg(short, long, value_enum, default_value_t = AuthorNotes::Inline)]
+    pub author_notes: AuthorNotes,
+
+    /// Wrap lines longer than this many characters. Lines aren't wrapped by default.
+    /// Words longer than a line (like urls) are never split.
+    #[arg(short, long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub wrap_width: Option<u16>,
+
+    /// The line endings to use. crlf is for old windows programs & devices that expect it.
+    #[arg(short, long, value_enum, default_value_t = LineEndings::Lf)]
+    pub line_endings: LineEndings,
+
+    /// Normalize the text to this unicode normalization form, E.G. nfkc to turn ligatures & fullwidth characters into plain ones.
+    #[arg(short, long, value_enum)]
+    pub normalization: Option<Normalization>,
+}
+
+/// The line endings plain text can be written with.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LineEndings {
+    /// \n, used by everything but windows.
+    Lf,
+
+    /// \r\n, used by windows.
+    Crlf,
+}
+
+/// The unicode normalization forms plain text can be normalized to.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Normalization {
+    /// Canonical composition.
+    Nfc,
+
+    /// Canonical decomposition.
+    Nfd,
+
+    /// Compatibility composition.
+    Nfkc,
+
+    /// Compatibility decomposition.
+    Nfkd,
+}
+
+/// struct that corresponds to arguments for Pdf generation.
+///
+/// The pdf uses the standard pdf fonts, which only cover WinAnsiEncoding (western european text).
+/// Any other characters are replaced with '?' & a warning is added for each chapter they're in.
+#[derive(Args, Debug)]
+pub struct PdfArgs {
+    /// Disable the inclusion of images.
+    /// Will speed up pdf generation and significantly decrease pdf size.
+    #[arg(short, long)]
+    pub no_images: bool,
+
+    /// The size of the pages.
+    #[arg(short, long, value_enum, default_value_t = PageSize::A4)]
+    pub page_size: PageSize,
+
+    /// The margin around each page in millimetres.
+    #[arg(short, long, default_value_t = 20.0)]
+    pub margin: f32,
+
+    /// The size of the body text in points. Headings are scaled to match.
+    #[arg(short, long, default_value_t = 11.0)]
+    pub font_size: f32,
+
+    /// Show the date each chapter was published next to its title.
+    #[arg(short = 'd', long)]
+    pub chapter_dates: bool,
+
+    /// Where to put the author's notes from before & after each chapter.
+    #[arg(short, long, value_enum, default_value_t = AuthorNotes::Inline)]
+    pub author_notes: AuthorNotes,
+
+    #[command(flatten)]
+    pub image_args: ImageArgs,
+}
+
+/// The page sizes a pdf can be generated with.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PageSize {
+    /// 210 x 297 mm.
+    A4,
+
+    /// 148 x 210 mm, for printing paperback sized books.
+    A5,
+
+    /// 8.5 x 11 inches.
+    Letter,
+
+    /// The screen of a 6" e-reader. Use a smaller margin & font size with this, E.G. -m 5 -f 9
+    Ereader6,
+}
 
 /// struct that corresponds to arguments for Markdown generation.
 #[derive(Args, Debug)]
@@ -62,6 +323,23 @@ pub struct MarkdownArgs {
     /// Disables the inclusion of html image tags in the markdown.
     #[arg(short, long)]
     pub no_image_tags: bool,
+
+    /// Write a directory with an index & a markdown file per chapter instead of one big file.
+    /// Each chapter gets YAML front matter & links to the previous & next chapters, E.G. for an Obsidian vault.
+    #[arg(short, long, conflicts_with = "update")]
+    pub split_chapters: bool,
+
+    /// Rebuild an existing markdown file, only downloading chapters that are new or have changed since the last run.
+    #[arg(short, long)]
+    pub update: bool,
+
+    /// Show the date each chapter was published next to its title.
+    #[arg(short = 'd', long)]
+    pub chapter_dates: bool,
+
+    /// Where to put the author's notes from before & after each chapter.
+    #[arg(short, long, value_enum, default_value_t = AuthorNotes::Inline)]
+    pub author_notes: AuthorNotes,
 }
 
 lazy_static! {
@@ -74,10 +352,157 @@ lazy_static! {
 /// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
 pub fn generate_audiobook(
     audiobook_args: AudiobookArgs,
+    download_args: DownloadArgs,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let tts_backend: Box<dyn TtsBackend> = match audiobook_args.tts_engine {
+        TtsEngine::EspeakNg => Box::new(EspeakNg {
+            voice: audiobook_args.voice.clone(),
+            words_per_minute: None,
+        }),
+        TtsEngine::Piper => match &audiobook_args.piper_model {
+            Some(model) => Box::new(Piper {
+                model: model.clone(),
+            }),
+            None => {
+                return Err(GenerationError::TtsModelMissingError {
+                    engine: "piper".to_string(),
+                })
+            },
+        },
+    };
+
+    generate_audiobook_with_backend(
+        audiobook_args,
+        download_args,
+        tts_backend.as_ref(),
+        book_url,
+        output_directory,
+    )
+}
+
+/// Generate an audiobook using the given text-to-speech backend, url, & outputs it to the output directory.
+///
+/// The tts_engine, voice & piper_model arguments are ignored in favour of the given backend.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_audiobook_with_backend(
+    audiobook_args: AudiobookArgs,
+    download_args: DownloadArgs,
+    tts_backend: &dyn TtsBackend,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let (fetcher, run_state) = setup_download(&download_args, &book_url, &output_directory, false)?;
+    let result = generate_audiobook_with_fetcher(
+        audiobook_args,
+        download_args,
+        tts_backend,
+        fetcher.as_ref(),
+        book_url,
+        output_directory,
+    );
+
+    run_state.finish(result)
+}
+
+/// Generate an audiobook using the given text-to-speech backend & fetcher, url, & outputs it to the output directory.
+///
+/// The tts_engine, voice & piper_model arguments are ignored in favour of the given backend.
+/// Every request goes through the given fetcher, so the cache, request & fixture download arguments are ignored.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_audiobook_with_fetcher(
+    audiobook_args: AudiobookArgs,
+    download_args: DownloadArgs,
+    tts_backend: &dyn TtsBackend,
+    fetcher: &dyn Fetcher,
     book_url: Url,
     output_directory: PathBuf,
 ) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
-    return Err(GenerationError::GenerationUnsupportedError);
+    let book = book::Book::new(fetcher, book_url, None, &download_args)?;
+    let file_extension = tts_backend.file_extension();
+
+    // Turn each chapter into the text that will be spoken.
+    let chapter_texts: Vec<String> = book
+        .chapters
+        .iter()
+        .map(|chapter| chapter_speech_text(chapter, &audiobook_args))
+        .collect();
+
+    // The appendix gets spoken after the last chapter.
+    let appendix_text = match audiobook_args.author_notes {
+        AuthorNotes::Appendix => book
+            .author_notes_appendix()
+            .map(|appendix| format!("Author's notes\n\n{0}", html::html_to_text(&appendix))),
+        _ => None,
+    };
+
+    println!("\nGenerating audio:");
+    // Spawn a progress bar showing how many chapters have been spoken.
+    let progress_bar = ProgressBar::new(chapter_texts.len().try_into().unwrap());
+    progress_bar.set_style(
+        ProgressStyle::with_template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent}%  ")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+
+    if audiobook_args.split_novel_by_chapters {
+        // Create a directory for the book and put one audio file per chapter in it.
+        let audiobook_directory =
+            convert_path_to_os_specific(output_directory.join(&book.file_name_title));
+        file_system_crap::create_new_directory(&audiobook_directory)?;
+
+        for (i, (chapter, text)) in book.chapters.iter().zip(chapter_texts).enumerate() {
+            let output_path = audiobook_directory.join(format!(
+                "{0:04} - {1}.{file_extension}",
+                i + 1,
+                file_system_crap::remove_illegal_chars(chapter.chapter_name.clone())
+            ));
+            tts_backend.synthesize(&text, &output_path)?;
+
+            progress_bar.inc(1);
+        }
+
+        if let Some(appendix_text) = &appendix_text {
+            let output_path = audiobook_directory.join(format!(
+                "{0:04} - Author's notes.{file_extension}",
+                book.chapters.len() + 1
+            ));
+            tts_backend.synthesize(appendix_text, &output_path)?;
+        }
+    } else {
+        let output_path = convert_path_to_os_specific(
+            output_directory.join(format!("{0}.{file_extension}", book.file_name_title)),
+        );
+        // The tts engine would happily overwrite an existing file, so check for one first.
+        if output_path.exists() {
+            return Err(GenerationError::FileCreationError {
+                error: std::io::ErrorKind::AlreadyExists.into(),
+                file_path: output_path,
+            });
+        }
+
+        let mut text = format!(
+            "{0}\n\nby: {1}\n\n{2}",
+            book.title,
+            book.author,
+            chapter_texts.join("\n\n")
+        );
+        if let Some(appendix_text) = &appendix_text {
+            text.push_str(&format!("\n\n{appendix_text}"));
+        }
+        tts_backend.synthesize(&text, &output_path)?;
+
+        progress_bar.inc(chapter_texts.len().try_into().unwrap());
+    }
+
+    progress_bar.finish();
+
+    Ok(WARNINGS.lock().unwrap())
 }
 
 /// Generate an epub file from the given arguments, url, & outputs it to the output directory.
@@ -86,11 +511,1030 @@ pub fn generate_audiobook(
 /// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
 pub fn generate_epub(
     epub_args: EpubArgs,
+    download_args: DownloadArgs,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let (fetcher, run_state) = setup_download(&download_args, &book_url, &output_directory, true)?;
+    let result = generate_epub_with_fetcher(
+        epub_args,
+        download_args,
+        fetcher.as_ref(),
+        book_url,
+        output_directory,
+    );
+
+    run_state.finish(result)
+}
+
+/// Generate an epub file from the given arguments & fetcher, url, & outputs it to the output directory.
+///
+/// Every request goes through the given fetcher, so the cache, request & fixture download arguments are ignored.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_epub_with_fetcher(
+    epub_args: EpubArgs,
+    download_args: DownloadArgs,
+    fetcher: &dyn Fetcher,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let book = book::Book::new(
+        fetcher,
+        book_url,
+        epub_args.update.then_some(output_directory.as_path()),
+        &download_args,
+    )?;
+
+    // Create the epub file. When updating, the existing epub gets replaced instead.
+    let output_path = convert_path_to_os_specific(
+        output_directory.join(format!("{0}.epub", book.file_name_title)),
+    );
+    let output_file = match OpenOptions::new()
+        .write(true)
+        .create_new(!epub_args.update)
+        .create(epub_args.update)
+        .truncate(epub_args.update)
+        .open(&output_path)
+    {
+        Ok(output_file) => output_file,
+        Err(error) => {
+            return Err(GenerationError::FileCreationError {
+                error,
+                file_path: output_path,
+            });
+        },
+    };
+
+    let mut output_file = write_epub(
+        &epub_args,
+        &download_args,
+        &book,
+        fetcher,
+        BufWriter::new(output_file),
+    )?;
+    if let Err(error) = output_file.flush() {
+        return Err(GenerationError::FileWriteError {
+            error,
+            file_path: output_path,
+        });
+    }
+
+    book.write_record(&output_directory)?;
+
+    Ok(WARNINGS.lock().unwrap())
+}
+
+/// Generate an epub from the given arguments & url, and stream it into the given writer.
+///
+/// Useful for sending the epub somewhere other than a file, like an http response.
+/// The output directory is still used for the run state, and for the record when updating.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_epub_to_writer<W: Write + Seek>(
+    epub_args: EpubArgs,
+    download_args: DownloadArgs,
+    book_url: Url,
+    output_directory: PathBuf,
+    writer: W,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let (fetcher, run_state) = setup_download(&download_args, &book_url, &output_directory, true)?;
+    let result = generate_epub_to_writer_with_fetcher(
+        epub_args,
+        download_args,
+        fetcher.as_ref(),
+        book_url,
+        output_directory,
+        writer,
+    );
+
+    run_state.finish(result)
+}
+
+/// Generate an epub from the given arguments & fetcher & url, and stream it into the given writer.
+///
+/// Every request goes through the given fetcher, so the cache, request & fixture download arguments are ignored.
+///
+/// Useful for sending the epub somewhere other than a file, like an http response.
+/// The output directory is still used for the run state, and for the record when updating.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_epub_to_writer_with_fetcher<W: Write + Seek>(
+    epub_args: EpubArgs,
+    download_args: DownloadArgs,
+    fetcher: &dyn Fetcher,
+    book_url: Url,
+    output_directory: PathBuf,
+    writer: W,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let book = book::Book::new(
+        fetcher,
+        book_url,
+        epub_args.update.then_some(output_directory.as_path()),
+        &download_args,
+    )?;
+
+    write_epub(&epub_args, &download_args, &book, fetcher, writer)?;
+
+    book.write_record(&output_directory)?;
+
+    Ok(WARNINGS.lock().unwrap())
+}
+
+/// Generate an html archive from the given arguments, url, & outputs it to the output directory.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_html(
+    html_args: HtmlArgs,
+    download_args: DownloadArgs,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let (fetcher, run_state) = setup_download(&download_args, &book_url, &output_directory, false)?;
+    let result = generate_html_with_fetcher(
+        html_args,
+        download_args,
+        fetcher.as_ref(),
+        book_url,
+        output_directory,
+    );
+
+    run_state.finish(result)
+}
+
+/// Generate an html archive from the given arguments & fetcher, url, & outputs it to the output directory.
+///
+/// Every request goes through the given fetcher, so the cache, request & fixture download arguments are ignored.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_html_with_fetcher(
+    html_args: HtmlArgs,
+    download_args: DownloadArgs,
+    fetcher: &dyn Fetcher,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let book = book::Book::new(fetcher, book_url, None, &download_args)?;
+
+    // Download the images before anything gets written.
+    // Images that couldn't be downloaded get a placeholder instead.
+    let downloaded_images = if html_args.no_images {
+        HashMap::new()
+    } else {
+        download_images(&book, fetcher, download_args.jobs)
+    };
+
+    // Create the directory the archive will be stored in. This will fail if it already exists.
+    // A single file archive doesn't need one.
+    let archive_directory =
+        convert_path_to_os_specific(output_directory.join(&book.file_name_title));
+    if !html_args.single_file {
+        file_system_crap::create_new_directory(&archive_directory)?;
+
+        file_system_crap::write_new_file(
+            &archive_directory.join("style.css"),
+            constants::HTML_CSS.as_bytes(),
+        )?;
+    }
+
+    // Download the cover image & store it next to the index page, or embed it.
+    let cover_image_tag = match fetcher.fetch(&book.cover_image_url) {
+        Ok(cover_image) if html_args.single_file => {
+            format!(
+                r#"<img src="{0}" alt="Cover"/>"#,
+                image_data_uri(&cover_image)
+            )
+        },
+        Ok(cover_image) => {
+            let (_, cover_file_extension) = cover_image.get_content_type_and_file_extension();
+            let cover_file_name = format!("cover.{cover_file_extension}");
+            file_system_crap::write_new_file(
+                &archive_directory.join(&cover_file_name),
+                &cover_image.get_bytes(),
+            )?;
+
+            format!(r#"<img src="{cover_file_name}" alt="Cover"/>"#)
+        },
+        Err(error) => {
+            add_image_download_warning(&book.cover_image_url, &error);
+            html::image_placeholder(&book.cover_image_url, Some("Cover"))
+        },
+    };
+
+    // Maps the image urls to where the images are stored, or their data uri if they are embedded.
+    let mut new_image_srcs: HashMap<Url, String> = HashMap::new();
+
+    if !html_args.no_images {
+        // Store the images in the images directory.
+        let images_directory = archive_directory.join("images");
+        if !html_args.single_file {
+            file_system_crap::create_new_directory(&images_directory)?;
+        }
+
+        if let Some(image_store) = &html_args.image_store {
+            fs::create_dir_all(image_store).map_err(|error| {
+                GenerationError::DirectoryCreationError {
+                    error,
+                    directory_path: image_store.clone(),
+                }
+            })?;
+        }
+
+        for (image_url, image) in &downloaded_images {
+            if html_args.single_file {
+                new_image_srcs.insert(image_url.clone(), image_data_uri(image));
+                continue;
+            }
+
+            let (_, image_file_extension) = image.get_content_type_and_file_extension();
+            let content_hash = image.content_hash();
+
+            // Images are named after their content, so the same image served from different urls is only stored once.
+            let image_file_name = format!("image_{}.{image_file_extension}", &content_hash[..16]);
+            let image_path = images_directory.join(&image_file_name);
+
+            if !image_path.exists() {
+                match &html_args.image_store {
+                    Some(image_store) => {
+                        let stored_image_path =
+                            image_store.join(format!("{content_hash}.{image_file_extension}"));
+                        if !stored_image_path.exists() {
+                            file_system_crap::write_new_file(
+                                &stored_image_path,
+                                &image.get_bytes(),
+                            )?;
+                        }
+
+                        file_system_crap::link_or_copy_file(&stored_image_path, &image_path)?;
+                    },
+                    None => file_system_crap::write_new_file(&image_path, &image.get_bytes())?,
+                }
+            }
+
+            new_image_srcs.insert(image_url.clone(), format!("images/{image_file_name}"));
+        }
+    }
+
+    // Point the images at their new srcs or remove them.
+    let replace_images = |html: &Html| -> String {
+        if html_args.no_images {
+            string_to_html_fragment(&remove_image_tags(html))
+                .root_element()
+                .inner_html()
+        } else {
+            html::replace_images(html, |image_url| new_image_srcs.get(image_url).cloned())
+                .root_element()
+                .inner_html()
+        }
+    };
+
+    if html_args.single_file {
+        let output_path = convert_path_to_os_specific(
+            output_directory.join(format!("{0}.html", book.file_name_title)),
+        );
+        let page = single_file_html_page(&book, &html_args, &cover_image_tag, replace_images);
+        file_system_crap::write_new_file(&output_path, page.as_bytes())?;
+
+        return Ok(WARNINGS.lock().unwrap());
+    }
+
+    // Generate the index page with the cover, book info & a list of all the chapters.
+    let mut chapter_list = String::new();
+    for (i, chapter) in book.chapters.iter().enumerate() {
+        chapter_list.push_str(&format!(
+            "<li><a href=\"chapter_{0}.html\">{1}</a></li>\n",
+            i + 1,
+            html::escape_html(&chapter.chapter_name)
+        ));
+    }
+
+    // The author's notes appendix gets its own page, listed after the chapters.
+    let author_notes_appendix = match html_args.author_notes {
+        AuthorNotes::Appendix => book.author_notes_appendix(),
+        _ => None,
+    };
+    if let Some(appendix) = &author_notes_appendix {
+        let appendix_body = format!(
+            "<div class=\"navigation\"><span></span><a href=\"index.html\">Index</a><span></span></div>\n<h1>Author's notes</h1>\n{0}",
+            replace_images(appendix)
+        );
+        file_system_crap::write_new_file(
+            &archive_directory.join("author_notes.html"),
+            html::wrap_html_page("Author's notes", &appendix_body).as_bytes(),
+        )?;
+
+        chapter_list.push_str("<li><a href=\"author_notes.html\">Author's notes</a></li>\n");
+    }
+
+    let book_info: String = book
+        .info()
+        .iter()
+        .map(|(label, value)| format!("<dt>{label}</dt><dd>{0}</dd>", html::escape_html(value)))
+        .collect();
+
+    let index_body = format!(
+        r#"<div class="cover">
+        <h1><a href="{0}">{1}</a></h1>
+        {2}
+        <h2>by: {3}</h2>
+        <h3>Archived on: {4}</h3></div>
+        <dl class="book-info">{5}</dl>
+        <div class="description">{6}</div>
+        <h2>Chapters</h2>
+        <ol class="chapter-list">
+        {7}</ol>"#,
+        book.book_url,
+        html::escape_html(&book.title),
+        cover_image_tag,
+        html::escape_html(&book.author),
+        Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        book_info,
+        book.description.root_element().inner_html(),
+        chapter_list
+    );
+    file_system_crap::write_new_file(
+        &archive_directory.join("index.html"),
+        html::wrap_html_page(&book.title, &index_body).as_bytes(),
+    )?;
+
+    // Generate a page for each chapter with links to the previous & next chapters.
+    for (i, chapter) in book.chapters.iter().enumerate() {
+        let chapter_html = replace_images(&chapter.html_with_author_notes(html_args.author_notes));
+
+        let previous_link = match i {
+            0 => String::from("<span></span>"),
+            _ => format!(r#"<a href="chapter_{i}.html">Previous</a>"#),
+        };
+        let next_link = if i + 1 < book.chapters.len() {
+            format!(r#"<a href="chapter_{0}.html">Next</a>"#, i + 2)
+        } else {
+            String::from("<span></span>")
+        };
+        let navigation = format!(
+            r#"<div class="navigation">{previous_link}<a href="index.html">Index</a>{next_link}</div>"#
+        );
+
+        let chapter_body = format!(
+            "{navigation}\n<h1>{0}</h1>\n<div class=\"chapter-content\">{chapter_html}</div>\n{navigation}",
+            html::escape_html(&chapter.chapter_name)
+        );
+
+        file_system_crap::write_new_file(
+            &archive_directory.join(format!("chapter_{}.html", i + 1)),
+            html::wrap_html_page(&chapter.chapter_name, &chapter_body).as_bytes(),
+        )?;
+    }
+
+    Ok(WARNINGS.lock().unwrap())
+}
+
+/// Generate a pdf file from the given arguments, url, & outputs it to the output directory.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_pdf(
+    pdf_args: PdfArgs,
+    download_args: DownloadArgs,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let (fetcher, run_state) = setup_download(&download_args, &book_url, &output_directory, false)?;
+    let result = generate_pdf_with_fetcher(
+        pdf_args,
+        download_args,
+        fetcher.as_ref(),
+        book_url,
+        output_directory,
+    );
+
+    run_state.finish(result)
+}
+
+/// Generate a pdf file from the given arguments & fetcher, url, & outputs it to the output directory.
+///
+/// Every request goes through the given fetcher, so the cache, request & fixture download arguments are ignored.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_pdf_with_fetcher(
+    pdf_args: PdfArgs,
+    download_args: DownloadArgs,
+    fetcher: &dyn Fetcher,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    // Check the layout before anything gets downloaded.
+    if !pdf::layout_fits(&pdf_args) {
+        return Err(GenerationError::PdfLayoutError {
+            page_size: pdf_args.page_size,
+        });
+    }
+
+    let book = book::Book::new(fetcher, book_url, None, &download_args)?;
+
+    // Download the images before the pdf is laid out, so they can be embedded afterwards.
+    // Images that couldn't be downloaded get a placeholder instead.
+    let mut downloaded_images: HashMap<Url, http::HttpResponse> = HashMap::new();
+
+    if !pdf_args.no_images {
+        downloaded_images = download_images(&book, fetcher, download_args.jobs);
+
+        match fetcher.fetch(&book.cover_image_url) {
+            Ok(cover_image) => {
+                downloaded_images.insert(book.cover_image_url.clone(), cover_image);
+            },
+            Err(error) => add_image_download_warning(&book.cover_image_url, &error),
+        }
+    }
+
+    let pdf_bytes = pdf::render_pdf(&book, &pdf_args, &downloaded_images);
+
+    let output_path = convert_path_to_os_specific(
+        output_directory.join(format!("{0}.pdf", book.file_name_title)),
+    );
+    file_system_crap::write_new_file(&output_path, &pdf_bytes)?;
+
+    Ok(WARNINGS.lock().unwrap())
+}
+
+/// Get the text that will be spoken for a chapter, starting with its title unless chapter titles are disabled.
+fn chapter_speech_text(chapter: &book::Chapter, audiobook_args: &AudiobookArgs) -> String {
+    let text = html::html_to_text(&chapter.html_with_author_notes(audiobook_args.author_notes));
+    if audiobook_args.no_chapter_titles {
+        text
+    } else {
+        format!("{}\n\n{text}", chapter.chapter_name)
+    }
+}
+
+/// Build a single html page holding the whole book: the cover & book info, a table of contents & every chapter.
+///
+/// The replace_images closure swaps the image tags for embedded ones (or removes them).
+fn single_file_html_page(
+    book: &book::Book,
+    html_args: &HtmlArgs,
+    cover_image_tag: &str,
+    replace_images: impl Fn(&Html) -> String,
+) -> String {
+    let mut table_of_contents = String::new();
+    let mut chapters = String::new();
+
+    for (volume, volume_chapters) in book.chapters_by_volume() {
+        // Volumes get a heading before their chapters & the chapters nested under them in the table of contents.
+        if let Some(volume) = volume {
+            table_of_contents.push_str(&format!(
+                "<li><a href=\"#volume-{0}\">{1}</a>\n<ol>\n",
+                volume.id,
+                html::escape_html(&volume.title)
+            ));
+            chapters.push_str(&format!(
+                "<h1 class=\"volume-title\" id=\"volume-{0}\">{1}</h1>\n",
+                volume.id,
+                html::escape_html(&volume.title)
+            ));
+        }
+
+        for (i, chapter) in volume_chapters {
+            table_of_contents.push_str(&format!(
+                "<li><a href=\"#chapter-{0}\">{1}</a></li>\n",
+                i + 1,
+                html::escape_html(&chapter.chapter_name)
+            ));
+            chapters.push_str(&format!(
+                "<section class=\"chapter\" id=\"chapter-{0}\">\n<h2>{1}</h2>\n<div class=\"chapter-content\">{2}</div>\n<div class=\"navigation\"><span></span><a href=\"#contents\">Contents</a><span></span></div>\n</section>\n",
+                i + 1,
+                html::escape_html(&chapter.chapter_name),
+                replace_images(&chapter.html_with_author_notes(html_args.author_notes))
+            ));
+        }
+
+        if volume.is_some() {
+            table_of_contents.push_str("</ol></li>\n");
+        }
+    }
+
+    // The author's notes appendix goes after the last chapter.
+    let author_notes_appendix = match html_args.author_notes {
+        AuthorNotes::Appendix => book.author_notes_appendix(),
+        _ => None,
+    };
+    if let Some(appendix) = &author_notes_appendix {
+        table_of_contents.push_str("<li><a href=\"#author-notes\">Author's notes</a></li>\n");
+        chapters.push_str(&format!(
+            "<section class=\"chapter\" id=\"author-notes\">\n<h2>Author's notes</h2>\n{0}\n</section>\n",
+            replace_images(appendix)
+        ));
+    }
+
+    let book_info: String = book
+        .info()
+        .iter()
+        .map(|(label, value)| format!("<dt>{label}</dt><dd>{0}</dd>", html::escape_html(value)))
+        .collect();
+
+    let body = format!(
+        r#"<div class="cover">
+        <h1><a href="{0}">{1}</a></h1>
+        {2}
+        <h2>by: {3}</h2>
+        <h3>Archived on: {4}</h3></div>
+        <dl class="book-info">{5}</dl>
+        <div class="description">{6}</div>
+        <h2 id="contents">Contents</h2>
+        <ol class="chapter-list">
+        {7}</ol>
+        {8}"#,
+        book.book_url,
+        html::escape_html(&book.title),
+        cover_image_tag,
+        html::escape_html(&book.author),
+        Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        book_info,
+        book.description.root_element().inner_html(),
+        table_of_contents,
+        chapters
+    );
+
+    html::wrap_self_contained_html_page(&book.title, &body)
+}
+
+/// Turn an image into a data uri, so it can be embedded straight into an html page.
+fn image_data_uri(image: &http::HttpResponse) -> String {
+    let (content_type, _) = image.get_content_type_and_file_extension();
+
+    format!(
+        "data:{content_type};base64,{0}",
+        base64::engine::general_purpose::STANDARD.encode(image.get_bytes())
+    )
+}
+
+/// Generate a markdown file from the given arguments, url, & outputs it to the output directory.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_markdown(
+    markdown_args: MarkdownArgs,
+    download_args: DownloadArgs,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let (fetcher, run_state) = setup_download(&download_args, &book_url, &output_directory, true)?;
+    let result = generate_markdown_with_fetcher(
+        markdown_args,
+        download_args,
+        fetcher.as_ref(),
+        book_url,
+        output_directory,
+    );
+
+    run_state.finish(result)
+}
+
+/// Generate a markdown file from the given arguments & fetcher, url, & outputs it to the output directory.
+///
+/// Every request goes through the given fetcher, so the cache, request & fixture download arguments are ignored.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_markdown_with_fetcher(
+    markdown_args: MarkdownArgs,
+    download_args: DownloadArgs,
+    fetcher: &dyn Fetcher,
     book_url: Url,
     output_directory: PathBuf,
 ) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
-    let book = book::Book::new(book_url)?;
+    let book = book::Book::new(
+        fetcher,
+        book_url,
+        markdown_args.update.then_some(output_directory.as_path()),
+        &download_args,
+    )?;
+
+    // Download the images before anything gets written.
+    let downloaded_images = if markdown_args.no_image_tags {
+        HashMap::new()
+    } else {
+        download_images(&book, fetcher, download_args.jobs)
+    };
+
+    if markdown_args.split_chapters {
+        write_markdown_directory(&markdown_args, &book, &downloaded_images, &output_directory)?;
+
+        book.write_record(&output_directory)?;
+
+        return Ok(WARNINGS.lock().unwrap());
+    }
+
+    let output_path =
+        convert_path_to_os_specific(output_directory.join(format!("{0}.md", book.file_name_title)));
+
+    // Create the md file. This will crash if it already exists or can not be created.
+    // When updating, the existing file gets replaced instead.
+    let mut output_file = match OpenOptions::new()
+        .write(true)
+        .create_new(!markdown_args.update)
+        .create(markdown_args.update)
+        .truncate(markdown_args.update)
+        .open(&output_path)
+    {
+        Ok(output_file) => output_file,
+        Err(error) => {
+            return Err(GenerationError::FileCreationError {
+                error,
+                file_path: output_path,
+            });
+        },
+    };
+
+    // Store the images in an assets directory next to the md file & point the image tags at them.
+    let new_image_srcs = if markdown_args.no_image_tags {
+        HashMap::new()
+    } else {
+        write_markdown_assets(&downloaded_images, &output_directory.join("assets"))?
+    };
+
+    // Append the book title & author.
+    let buf = format!("{}\n\nby: {}", &book.title, &book.author);
+    output_file.write_all(buf.as_bytes()).unwrap();
+
+    let buf = format!(
+        "\nArchived on: {}\n\n",
+        Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+    );
+    output_file.write_all(buf.as_bytes()).unwrap();
+
+    // Append the rest of the book's metadata, one line each.
+    let buf: String = book
+        .info()
+        .iter()
+        .map(|(label, value)| format!("{label}: {value}  \n"))
+        .collect();
+    output_file
+        .write_all(format!("{buf}\n").as_bytes())
+        .unwrap();
+
+    for (volume, chapters) in book.chapters_by_volume() {
+        // Give each volume its own heading before its chapters.
+        if let Some(volume) = volume {
+            let buf = format!("# {0}\n\n", volume.title);
+            output_file.write_all(buf.as_bytes()).unwrap();
+        }
+
+        for (_, chapter) in chapters {
+            let mut buf;
+
+            if !markdown_args.no_chapter_titles {
+                buf = format!("----\n{}", chapter.heading(markdown_args.chapter_dates));
+                output_file.write_all(buf.as_bytes()).unwrap();
+            }
 
+            let chapter_html = chapter.html_with_author_notes(markdown_args.author_notes);
+            if markdown_args.no_image_tags {
+                // Remove image tags or not depending on args.
+                buf = format!(
+                    "\n\n{}\n\n",
+                    html2md::parse_html(&html::remove_image_tags(&chapter_html))
+                );
+            } else {
+                buf = format!(
+                    "\n\n{}\n\n",
+                    html2md::parse_html(&replace_image_tags(&chapter_html, &new_image_srcs))
+                );
+            }
+
+            output_file.write_all(buf.as_bytes()).unwrap();
+        }
+    }
+
+    // Append the author's notes appendix after the last chapter.
+    if markdown_args.author_notes == AuthorNotes::Appendix {
+        if let Some(appendix) = book.author_notes_appendix() {
+            let appendix_markdown = if markdown_args.no_image_tags {
+                html2md::parse_html(&html::remove_image_tags(&appendix))
+            } else {
+                html2md::parse_html(&replace_image_tags(&appendix, &new_image_srcs))
+            };
+
+            let buf = format!("----\nAuthor's notes\n\n{appendix_markdown}\n\n");
+            output_file.write_all(buf.as_bytes()).unwrap();
+        }
+    }
+
+    book.write_record(&output_directory)?;
+
+    Ok(WARNINGS.lock().unwrap())
+}
+
+/// Generate a plain text file from the given arguments, url, & outputs it to the output directory.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_txt(
+    txt_args: TxtArgs,
+    download_args: DownloadArgs,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let (fetcher, run_state) = setup_download(&download_args, &book_url, &output_directory, true)?;
+    let result = generate_txt_with_fetcher(
+        txt_args,
+        download_args,
+        fetcher.as_ref(),
+        book_url,
+        output_directory,
+    );
+
+    run_state.finish(result)
+}
+
+/// Generate a plain text file from the given arguments & fetcher, url, & outputs it to the output directory.
+///
+/// Every request goes through the given fetcher, so the cache, request & fixture download arguments are ignored.
+///
+/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
+/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
+pub fn generate_txt_with_fetcher(
+    txt_args: TxtArgs,
+    download_args: DownloadArgs,
+    fetcher: &dyn Fetcher,
+    book_url: Url,
+    output_directory: PathBuf,
+) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+    let book = book::Book::new(
+        fetcher,
+        book_url,
+        txt_args.update.then_some(output_directory.as_path()),
+        &download_args,
+    )?;
+
+    let output_path = convert_path_to_os_specific(
+        output_directory.join(format!("{0}.txt", book.file_name_title)),
+    );
+
+    // Create the txt file. This will crash if it already exists or can not be created.
+    // When updating, the existing file gets replaced instead.
+    let mut output_file = match OpenOptions::new()
+        .write(true)
+        .create_new(!txt_args.update)
+        .create(txt_args.update)
+        .truncate(txt_args.update)
+        .open(&output_path)
+    {
+        Ok(output_file) => output_file,
+        Err(error) => {
+            return Err(GenerationError::FileCreationError {
+                error,
+                file_path: output_path,
+            });
+        },
+    };
+
+    let html_to_text =
+        |html: &Html| html::html_to_text_with_scene_breaks(html, Some(constants::TEXT_SCENE_BREAK));
+
+    // The book title, author & metadata, one line each.
+    let mut text = format!(
+        "{0}\n\nby: {1}\nArchived on: {2}\n\n",
+        book.title,
+        book.author,
+        Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+    );
+    for (label, value) in book.info() {
+        text.push_str(&format!("{label}: {value}\n"));
+    }
+    text.push_str(&format!("\n{0}\n", html_to_text(&book.description)));
+
+    for (volume, chapters) in book.chapters_by_volume() {
+        // Give each volume its own title before its chapters.
+        if let Some(volume) = volume {
+            text.push_str(&format!("\n\n\n{0}\n", volume.title));
+        }
+
+        for (_, chapter) in chapters {
+            text.push_str("\n\n");
+            if !txt_args.no_chapter_titles {
+                text.push_str(&format!("{0}\n\n", chapter.heading(txt_args.chapter_dates)));
+            }
+
+            text.push_str(&html_to_text(
+                &chapter.html_with_author_notes(txt_args.author_notes),
+            ));
+            text.push('\n');
+        }
+    }
+
+    // Append the author's notes appendix after the last chapter.
+    if txt_args.author_notes == AuthorNotes::Appendix {
+        if let Some(appendix) = book.author_notes_appendix() {
+            text.push_str(&format!(
+                "\n\nAuthor's notes\n\n{0}\n",
+                html_to_text(&appendix)
+            ));
+        }
+    }
+
+    if let Err(error) = output_file.write_all(format_text(text, &txt_args).as_bytes()) {
+        return Err(GenerationError::FileWriteError {
+            error,
+            file_path: output_path,
+        });
+    }
+
+    book.write_record(&output_directory)?;
+
+    Ok(WARNINGS.lock().unwrap())
+}
+
+/// Write the downloaded images of a book into the assets directory, so the markdown doesn't depend on the image hosts.
+///
+/// Returns a map of the image urls to the paths of the assets, which the image tags get pointed at.
+fn write_markdown_assets(
+    downloaded_images: &HashMap<Url, http::HttpResponse>,
+    assets_directory: &Path,
+) -> Result<HashMap<Url, String>, GenerationError> {
+    let mut new_image_srcs: HashMap<Url, String> = HashMap::new();
+    if downloaded_images.is_empty() {
+        return Ok(new_image_srcs);
+    }
+
+    // The assets directory is shared by every markdown file in the output directory, so it might exist already.
+    fs::create_dir_all(assets_directory).map_err(|error| {
+        GenerationError::DirectoryCreationError {
+            error,
+            directory_path: assets_directory.to_path_buf(),
+        }
+    })?;
+
+    for (image_url, image) in downloaded_images {
+        let (_, image_file_extension) = image.get_content_type_and_file_extension();
+
+        // Images are named after their content, so the same image is only stored once, even across books.
+        let image_file_name = format!(
+            "image_{0}.{image_file_extension}",
+            &image.content_hash()[..16]
+        );
+        let image_path = assets_directory.join(&image_file_name);
+        if !image_path.exists() {
+            file_system_crap::write_new_file(&image_path, &image.get_bytes())?;
+        }
+
+        new_image_srcs.insert(image_url.clone(), format!("assets/{image_file_name}"));
+    }
+
+    Ok(new_image_srcs)
+}
+
+/// Point the images in some html at their new srcs, returning the html as a string.
+///
+/// Images without a new src couldn't be downloaded, so they get a placeholder instead.
+fn replace_image_tags(html: &Html, new_image_srcs: &HashMap<Url, String>) -> String {
+    html::replace_images(html, |image_url| new_image_srcs.get(image_url).cloned()).html()
+}
+
+/// Write a book as a directory of markdown files: an index linking to every chapter, then a file per chapter.
+///
+/// Each chapter file starts with YAML front matter & has links to the previous & next chapters.
+fn write_markdown_directory(
+    markdown_args: &MarkdownArgs,
+    book: &book::Book,
+    downloaded_images: &HashMap<Url, http::HttpResponse>,
+    output_directory: &Path,
+) -> Result<(), GenerationError> {
+    // Create the directory the markdown files will be stored in. This will fail if it already exists.
+    let markdown_directory =
+        convert_path_to_os_specific(output_directory.join(&book.file_name_title));
+    file_system_crap::create_new_directory(&markdown_directory)?;
+
+    let new_image_srcs = if markdown_args.no_image_tags {
+        HashMap::new()
+    } else {
+        write_markdown_assets(downloaded_images, &markdown_directory.join("assets"))?
+    };
+
+    let to_markdown = |html: &Html| -> String {
+        if markdown_args.no_image_tags {
+            html2md::parse_html(&html::remove_image_tags(html))
+        } else {
+            html2md::parse_html(&replace_image_tags(html, &new_image_srcs))
+        }
+    };
+
+    // Strings are written as json strings, which are valid YAML & take care of the escaping.
+    let yaml_string = |string: &str| serde_json::to_string(string).unwrap();
+
+    let author_notes_appendix = match markdown_args.author_notes {
+        AuthorNotes::Appendix => book.author_notes_appendix(),
+        _ => None,
+    };
+
+    // The index has the book info & the chapters listed under their volumes.
+    let mut index = format!(
+        "---\ntitle: {0}\nauthor: {1}\nurl: {2}\narchived: {3}\n---\n\n# {4}\n\nby: {5}\n\n",
+        yaml_string(&book.title),
+        yaml_string(&book.author),
+        yaml_string(book.book_url.as_str()),
+        yaml_string(&Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)),
+        book.title,
+        book.author
+    );
+    for (label, value) in book.info() {
+        index.push_str(&format!("{label}: {value}  \n"));
+    }
+    index.push_str(&format!(
+        "\n{0}\n\n## Chapters\n\n",
+        to_markdown(&book.description)
+    ));
+
+    for (volume, chapters) in book.chapters_by_volume() {
+        if let Some(volume) = volume {
+            index.push_str(&format!("### {0}\n\n", volume.title));
+        }
+
+        for (i, chapter) in chapters {
+            // Brackets in the title would end the link text early.
+            let link_text = chapter
+                .heading(markdown_args.chapter_dates)
+                .replace('[', "\\[")
+                .replace(']', "\\]");
+            index.push_str(&format!("{0}. [{link_text}](chapter_{0}.md)\n", i + 1));
+        }
+        index.push('\n');
+    }
+
+    if let Some(appendix) = &author_notes_appendix {
+        index.push_str("[Author's notes](author_notes.md)\n");
+
+        let appendix_markdown = format!(
+            "[Index](index.md)\n\n# Author's notes\n\n{0}\n",
+            to_markdown(appendix)
+        );
+        file_system_crap::write_new_file(
+            &markdown_directory.join("author_notes.md"),
+            appendix_markdown.as_bytes(),
+        )?;
+    }
+
+    file_system_crap::write_new_file(&markdown_directory.join("index.md"), index.as_bytes())?;
+
+    for (i, chapter) in book.chapters.iter().enumerate() {
+        let mut front_matter = format!(
+            "---\ntitle: {0}\nchapter_number: {1}\nurl: {2}\n",
+            yaml_string(&chapter.chapter_name),
+            i + 1,
+            yaml_string(chapter.metadata.url.as_str())
+        );
+        if let Some(date) = chapter.metadata.date {
+            front_matter.push_str(&format!("published: {0}\n", date.to_rfc3339()));
+        }
+        // Only the chapter itself counts towards the word count, not the author's notes.
+        let word_count = html::html_to_text(&chapter.isolated_chapter_html)
+            .split_whitespace()
+            .count();
+        front_matter.push_str(&format!("word_count: {word_count}\n---\n\n"));
+
+        let previous_link = match i {
+            0 => String::new(),
+            _ => format!("[Previous](chapter_{i}.md) | "),
+        };
+        let next_link = if i + 1 < book.chapters.len() {
+            format!(" | [Next](chapter_{0}.md)", i + 2)
+        } else {
+            String::new()
+        };
+        let navigation = format!("{previous_link}[Index](index.md){next_link}");
+
+        let heading = if markdown_args.no_chapter_titles {
+            String::new()
+        } else {
+            format!("# {0}\n\n", chapter.heading(markdown_args.chapter_dates))
+        };
+
+        let chapter_markdown = format!(
+            "{front_matter}{navigation}\n\n{heading}{0}\n\n{navigation}\n",
+            to_markdown(&chapter.html_with_author_notes(markdown_args.author_notes))
+        );
+        file_system_crap::write_new_file(
+            &markdown_directory.join(format!("chapter_{}.md", i + 1)),
+            chapter_markdown.as_bytes(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Build the epub for a book & stream it into the given writer, returning the writer once the epub is finished.
+fn write_epub<W: Write + Seek>(
+    epub_args: &EpubArgs,
+    download_args: &DownloadArgs,
+    book: &book::Book,
+    fetcher: &dyn Fetcher,
+    writer: W,
+) -> Result<W, GenerationError> {
     // Initialize the epub builder.
     let mut epub_builder = EpubBuilder::new(ZipLibrary::new().unwrap()).unwrap();
 
@@ -104,36 +1548,69 @@ pub fn generate_epub(
     epub_builder
         .metadata("title", &book.title)
         .expect("Unable to add title metadata");
+    epub_builder
+        .metadata("description", html::html_to_text(&book.description))
+        .expect("Unable to add description metadata");
+    for tag in &book.tags {
+        epub_builder
+            .metadata("subject", tag)
+            .expect("Unable to add subject metadata");
+    }
+
+    // The images are only added as empty placeholders for now, and get streamed into the finished epub at the end.
+    // This keeps them out of memory. Maps the placeholder's path in the epub to the image.
+    let mut image_placeholders: HashMap<String, ImagePlaceholder> = HashMap::new();
 
     // Download the cover image & add it to the epub.
-    let cover_image = http::get_response(book.cover_image_url)?;
-    let (cover_mime_type, cover_file_extension) = cover_image.get_content_type_and_file_extension();
-    epub_builder
-        .add_cover_image(
-            format!("cover.{cover_file_extension}"),
-            cover_image.get_bytes()?.to_vec().as_slice(),
-            cover_mime_type,
-        )
-        .expect("Error! Unable to add cover image.");
+    let cover_image = match fetcher.fetch(&book.cover_image_url) {
+        Ok(cover_image) => {
+            prepare_epub_image(&book.cover_image_url, cover_image, &epub_args.image_args)
+        },
+        Err(error) => {
+            add_image_download_warning(&book.cover_image_url, &error);
+            None
+        },
+    };
+    let cover_image_tag = match cover_image {
+        Some((cover_placeholder, cover_mime_type, cover_file_extension)) => {
+            epub_builder
+                .add_cover_image(
+                    format!("cover.{cover_file_extension}"),
+                    io::empty(),
+                    cover_mime_type,
+                )
+                .expect("Error! Unable to add cover image.");
+            image_placeholders.insert(
+                format!("OEBPS/cover.{cover_file_extension}"),
+                cover_placeholder,
+            );
+
+            format!(r#"<img src="cover.{cover_file_extension}" alt="Cover"/>"#)
+        },
+        None => html::image_placeholder(&book.cover_image_url, Some("Cover")),
+    };
 
     // Generate the cover xhtml.
-    let cover_xhtml = format!(
-        r#"<head></head><body><div style="text-align: center;">
+    let book_info: String = book
+        .info()
+        .iter()
+        .map(|(label, value)| format!("<p>{label}: {0}</p>", html::escape_html(value)))
+        .collect();
+    let cover_html = format!(
+        r#"<div style="text-align: center;">
         <h1><a href="{0}">{1}</a></h1>
-        <img src="cover.{2}"/>
+        {2}
         <h2>by: {3}</h2>
-        <h3>Archived on: {4}</h3></div></body>"#,
+        <h3>Archived on: {4}</h3>
+        {5}</div>"#,
         book.book_url,
-        book.title,
-        cover_file_extension,
-        book.author,
-        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
-    );
-    let cover_xhtml = format!(
-        "{0}{cover_xhtml}{1}",
-        constants::EPUB_XML_HEAD,
-        constants::EPUB_XML_TAIL
+        html::escape_html(&book.title),
+        cover_image_tag,
+        html::escape_html(&book.author),
+        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        book_info
     );
+    let cover_xhtml = html_to_xhtml(&string_to_html_fragment(&cover_html), "Cover");
 
     // Add the cover xhtml to the epub.
     epub_builder
@@ -147,10 +1624,8 @@ pub fn generate_epub(
     // Add a table of contents after the cover page.
     epub_builder.inline_toc();
 
-    // Setup html2xhtml on the operating system.
-    let html2xhtml_temp_dir = file_system_crap::setup_html2xhtml()?;
-
-    let mut old_tags_new_tags: HashMap<String, String> = HashMap::new();
+    // Maps the image urls to the names of the images in the epub.
+    let mut new_image_srcs: HashMap<Url, String> = HashMap::new();
 
     if !epub_args.no_images {
         // Download the images and add em to the epub.
@@ -167,178 +1642,400 @@ pub fn generate_epub(
             .progress_chars("#>-"),
         );
 
-        let mut i: usize = 0;
-        for image_url in book.image_urls_and_tags.keys() {
-            let image = http::get_response(image_url.clone())?;
-            let (image_mime_type, image_file_extension) =
-                image.get_content_type_and_file_extension();
-            epub_builder
-                .add_resource(
-                    format!("image_{i}.{image_file_extension}"),
-                    image.get_bytes()?.to_vec().reader(),
-                    image_mime_type,
-                )
-                .expect("Error! Unable to add content image");
-
-            for image_tag in book.image_urls_and_tags[image_url].clone() {
-                old_tags_new_tags.insert(
-                    image_tag.clone(),
-                    html::replace_img_src(image_tag, format!("image_{i}.{image_file_extension}")),
-                );
-            }
-
-            i += 1;
-            progress_bar.inc(1);
-        }
+        let image_urls: Vec<&Url> = book.image_urls_and_tags.keys().collect();
+
+        misc::parallel_for_each(
+            &image_urls,
+            download_args.jobs,
+            |image_url| {
+                let image = download_image(fetcher, image_url, &progress_bar)?;
+                let content_hash = image.content_hash();
+                prepare_epub_image(image_url, image, &epub_args.image_args)
+                    .map(|prepared_image| (prepared_image, content_hash))
+            },
+            |i, image| {
+                progress_bar.inc(1);
+
+                // Images that couldn't be downloaded or can't go in an epub get a placeholder instead.
+                let Some(((image, image_mime_type, image_file_extension), content_hash)) = image
+                else {
+                    return;
+                };
+
+                // Images are named after their content, so the same image served from different urls is only added once.
+                let image_file_name =
+                    format!("image_{}.{image_file_extension}", &content_hash[..16]);
+                if let Entry::Vacant(placeholder) =
+                    image_placeholders.entry(format!("OEBPS/{image_file_name}"))
+                {
+                    epub_builder
+                        .add_resource(&image_file_name, io::empty(), image_mime_type)
+                        .expect("Error! Unable to add content image");
+                    placeholder.insert(image);
+                }
+
+                new_image_srcs.insert(image_urls[i].clone(), image_file_name);
+            },
+        );
 
         progress_bar.finish();
     }
 
-    // Convert the html to xhtml and add the xhtml to the epub for each chapter.
-    for (i, chapter) in book.chapters.iter().enumerate() {
-        let xhtml: String;
+    // Convert the html to xhtml, pointing the images at the ones in the epub or removing them.
+    let convert_to_xhtml = |html: &Html, title: &str| -> String {
         if epub_args.no_images {
-            xhtml = html_to_xhtml(
-                string_to_html_fragment(&remove_image_tags(&chapter.isolated_chapter_html)),
-                &html2xhtml_temp_dir,
-            )?
+            html_to_xhtml(&string_to_html_fragment(&remove_image_tags(html)), title)
         } else {
-            let mut replaced_html = chapter.isolated_chapter_html.html();
-            for old_img_tag in old_tags_new_tags.keys() {
-                replaced_html =
-                    replaced_html.replace(&old_img_tag.clone(), &old_tags_new_tags[old_img_tag]);
-            }
+            // Reparse the html, so placeholders that ended up inside paragraphs get moved out of them.
+            let replaced_html = replace_image_tags(html, &new_image_srcs);
+            html_to_xhtml(&string_to_html_fragment(&replaced_html), title)
+        }
+    };
 
-            xhtml = html_to_xhtml(
-                string_to_html_fragment(&replaced_html),
-                &html2xhtml_temp_dir,
-            )?;
+    // Add the xhtml to the epub for each chapter.
+    // Chapters in a volume come after the volume's title page & get nested under it in the table of contents.
+    for (volume_number, (volume, chapters)) in book.chapters_by_volume().into_iter().enumerate() {
+        let chapter_level = match volume {
+            Some(volume) => {
+                let volume_xhtml = html_to_xhtml(
+                    &string_to_html_fragment(&format!(
+                        r#"<div style="text-align: center;"><h1>{0}</h1></div>"#,
+                        html::escape_html(&volume.title)
+                    )),
+                    &volume.title,
+                );
+
+                epub_builder
+                    .add_content(
+                        EpubContent::new(
+                            format!("volume_{}.xhtml", volume_number + 1),
+                            volume_xhtml.as_bytes(),
+                        )
+                        .title(volume.title.clone())
+                        .reftype(ReferenceType::Text),
+                    )
+                    .expect("Error! Unable to add volume");
+
+                2
+            },
+            None => 1,
+        };
+
+        for (i, chapter) in chapters {
+            let xhtml = convert_to_xhtml(
+                &chapter.html_with_author_notes(epub_args.author_notes),
+                &chapter.chapter_name,
+            );
+
+            epub_builder
+                .add_content(
+                    EpubContent::new(format!("chapter_{}.xhtml", i + 1), xhtml.as_bytes())
+                        .title(chapter.heading(epub_args.chapter_dates))
+                        .level(chapter_level)
+                        .reftype(ReferenceType::Text),
+                )
+                .expect("Error! Unable to add chapter");
         }
+    }
 
-        epub_builder
-            .add_content(
-                EpubContent::new(format!("chapter_{}.xhtml", i + 1), xhtml.as_bytes())
-                    .title(chapter.chapter_name.clone())
-                    .reftype(ReferenceType::Text),
-            )
-            .expect("Error! Unable to add chapter");
+    // Add the author's notes appendix after the last chapter.
+    if epub_args.author_notes == AuthorNotes::Appendix {
+        if let Some(appendix) = book.author_notes_appendix() {
+            let xhtml = convert_to_xhtml(&appendix, "Author's notes");
+
+            epub_builder
+                .add_content(
+                    EpubContent::new("author_notes.xhtml", xhtml.as_bytes())
+                        .title("Author's notes")
+                        .reftype(ReferenceType::Text),
+                )
+                .expect("Error! Unable to add the author's notes");
+        }
     }
 
-    // Generate the finished epub data as a byte vector.
-    let mut finished_epub: Vec<u8> = vec![];
+    // Generate the epub without the images. This is small since it's only text.
+    let mut epub_without_images: Vec<u8> = vec![];
     epub_builder
-        .generate(&mut finished_epub)
+        .generate(&mut epub_without_images)
         .expect("Unable to generate epub data");
 
-    // Create the epub file and write the finished epub data to it.
-    let output_path = convert_path_to_os_specific(
-        output_directory.join(format!("{0}.epub", book.file_name_title)),
-    );
-    let mut output_file = match OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&output_path)
-    {
-        Ok(output_file) => output_file,
-        Err(error) => {
-            eprintln!(
-                "Error! Unable to create: {0}\n{error}",
-                output_path.to_string_lossy()
-            );
-            exit(1);
-        },
-    };
-
-    output_file.write_all(finished_epub.as_slice()).expect(
-        format!(
-            "Unable to write finished epub data to {0}",
-            output_path.to_string_lossy()
-        )
-        .as_str(),
-    );
+    stream_epub(&epub_without_images, &image_placeholders, writer)
+}
 
-    // Delete the html2xhtml temp directory. It's good to clean up after yourself.
-    file_system_crap::delete_temp_dir(html2xhtml_temp_dir);
+/// An image that's left out of the epub until it gets streamed in at the end.
+struct ImagePlaceholder {
+    /// The downloaded image. Cached images are only read from disk when they're streamed in.
+    image: http::HttpResponse,
 
-    Ok(WARNINGS.lock().unwrap())
+    /// The image after processing (resizing, converting, etc.), or None if the downloaded image is used as is.
+    processed_image: Option<Vec<u8>>,
 }
 
-/// Generate an html archive from the given arguments, url, & outputs it to the output directory.
+/// Prepare an image for the epub, processing it if needed.
 ///
-/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
-/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
-pub fn generate_html(
-    html_args: HtmlArgs,
-    book_url: Url,
-    output_directory: PathBuf,
-) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
-    return Err(GenerationError::GenerationUnsupportedError);
+/// Returns the image along with the content(mime)-type & file extension it actually ends up with,
+/// so an image that fails to convert keeps its original type.
+/// Returns None & adds a warning if it's a type the epub spec doesn't allow & that can't be converted (like avif),
+/// so it gets a placeholder instead.
+fn prepare_epub_image(
+    image_url: &Url,
+    image: http::HttpResponse,
+    image_args: &ImageArgs,
+) -> Option<(ImagePlaceholder, String, String)> {
+    let (content_type, file_extension) = image.get_content_type_and_file_extension();
+
+    let (processed_image, content_type, file_extension) =
+        if image_args.is_enabled() || images::needs_conversion(&content_type) {
+            let (processed_image, content_type, file_extension) =
+                process_image(image_url, &image, image_args);
+            (Some(processed_image), content_type, file_extension)
+        } else {
+            (None, content_type, file_extension)
+        };
+
+    if !images::is_epub_image_type(&content_type) {
+        let warning = Warning::UnsupportedImageType {
+            warning_msg: "This type of image isn't supported by the epub spec & can't be converted, using a placeholder".to_string(),
+            url: image_url.clone(),
+            content_type,
+        };
+        WARNINGS.lock().unwrap().add_warning(warning);
+
+        return None;
+    }
+
+    let placeholder = ImagePlaceholder {
+        image,
+        processed_image,
+    };
+    Some((placeholder, content_type, file_extension))
 }
 
-/// Generate a markdown file from the given arguments, url, & outputs it to the output directory.
+/// Copy an epub into the writer, streaming the image for each placeholder into the epub from the cache or disk as it goes.
 ///
-/// This function DOES NOT do any error checking on the Url or output directory & WILL panic if they are wrong.
-/// Make sure the Url is valid and the output directory is writable BEFORE passing them to this.
-pub fn generate_markdown(
-    markdown_args: MarkdownArgs,
-    book_url: Url,
-    output_directory: PathBuf,
-) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
-    let book = book::Book::new(book_url)?;
+/// Every other file is copied over as is, so the order (and the uncompressed mimetype file being first) is kept.
+/// Processed images are already in memory, so they're copied from there instead.
+fn stream_epub<W: Write + Seek>(
+    epub: &[u8],
+    image_placeholders: &HashMap<String, ImagePlaceholder>,
+    writer: W,
+) -> Result<W, GenerationError> {
+    let mut epub = zip::ZipArchive::new(Cursor::new(epub))
+        .map_err(|error| GenerationError::EpubWriteError { error })?;
+    let mut zip_writer = zip::ZipWriter::new(writer);
+
+    for i in 0..epub.len() {
+        let file = epub
+            .by_index_raw(i)
+            .map_err(|error| GenerationError::EpubWriteError { error })?;
+
+        let image_placeholder = match image_placeholders.get(file.name()) {
+            Some(image_placeholder) => image_placeholder,
+            None => {
+                zip_writer
+                    .raw_copy_file(file)
+                    .map_err(|error| GenerationError::EpubWriteError { error })?;
+                continue;
+            },
+        };
+
+        let file_name = file.name().to_string();
+        drop(file);
+
+        let mut image: Box<dyn io::Read> = match &image_placeholder.processed_image {
+            Some(processed_image) => Box::new(processed_image.as_slice()),
+            None => image_placeholder
+                .image
+                .clone()
+                .into_reader()
+                .map_err(|error| GenerationError::EpubWriteError {
+                    error: error.into(),
+                })?,
+        };
+
+        zip_writer
+            .start_file(file_name, zip::write::FileOptions::default())
+            .map_err(|error| GenerationError::EpubWriteError { error })?;
+        io::copy(&mut image, &mut zip_writer).map_err(|error| GenerationError::EpubWriteError {
+            error: error.into(),
+        })?;
+    }
 
-    let output_path =
-        convert_path_to_os_specific(output_directory.join(format!("{0}.md", book.file_name_title)));
+    zip_writer
+        .finish()
+        .map_err(|error| GenerationError::EpubWriteError { error })
+}
 
-    // Create the md file. This will crash if it already exists or can not be created.
-    let mut output_file = match OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&output_path)
-    {
-        Ok(output_file) => output_file,
-        Err(error) => {
-            return Err(GenerationError::FileCreationError {
-                error,
-                file_path: output_path,
-            });
+/// Normalize, wrap & set the line endings of plain text according to the txt args.
+fn format_text(text: String, txt_args: &TxtArgs) -> String {
+    // Normalize first, since it can change how long the lines are.
+    let text = match txt_args.normalization {
+        Some(Normalization::Nfc) => text.nfc().collect(),
+        Some(Normalization::Nfd) => text.nfd().collect(),
+        Some(Normalization::Nfkc) => text.nfkc().collect(),
+        Some(Normalization::Nfkd) => text.nfkd().collect(),
+        None => text,
+    };
+
+    let text = match txt_args.wrap_width {
+        Some(wrap_width) => {
+            // Only break lines at spaces, so hyphenated words, dates & urls stay whole.
+            let options = textwrap::Options::new(wrap_width.into())
+                .word_separator(textwrap::WordSeparator::AsciiSpace)
+                .word_splitter(textwrap::WordSplitter::NoHyphenation)
+                .break_words(false);
+            text.split('\n')
+                .map(|line| textwrap::fill(line, &options))
+                .collect::<Vec<String>>()
+                .join("\n")
         },
+        None => text,
     };
 
-    // Append the book title & author.
-    let buf = format!("{}\n\nby: {}", &book.title, &book.author);
-    output_file.write_all(buf.as_bytes()).unwrap();
+    match txt_args.line_endings {
+        LineEndings::Lf => text,
+        LineEndings::Crlf => text.replace('\n', "\r\n"),
+    }
+}
 
-    let buf = format!(
-        "\nArchived on: {}\n\n",
-        Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+/// Process an image according to the image args, returning it along with its content(mime)-type & file extension.
+///
+/// If the image can't be processed a warning is added and the original image is used, keeping its original type.
+fn process_image(
+    image_url: &Url,
+    image: &http::HttpResponse,
+    image_args: &ImageArgs,
+) -> (Vec<u8>, String, String) {
+    let image_bytes = image.get_bytes();
+    let (content_type, file_extension) = image.get_content_type_and_file_extension();
+
+    match images::process_image(&image_bytes, image_args) {
+        Ok((processed_image, true)) => {
+            let (content_type, file_extension) =
+                image_args.output_content_type_and_file_extension(content_type, file_extension);
+            (processed_image, content_type, file_extension)
+        },
+        Ok((processed_image, false)) => (processed_image, content_type, file_extension),
+        Err(error) => {
+            let warning = Warning::ImageProcessingError {
+                warning_msg: "Unable to process image, using the original".to_string(),
+                url: image_url.clone(),
+                error: error.to_string(),
+            };
+            WARNINGS.lock().unwrap().add_warning(warning);
+
+            (image_bytes.to_vec(), content_type, file_extension)
+        },
+    }
+}
+
+/// Download all the images of a book, showing a progress bar.
+///
+/// Returns the downloaded images by url. Cached images are left on disk until they're used.
+/// Images that couldn't be downloaded are left out, so they can get a placeholder instead.
+fn download_images(
+    book: &book::Book,
+    fetcher: &dyn Fetcher,
+    jobs: usize,
+) -> HashMap<Url, http::HttpResponse> {
+    let mut downloaded_images: HashMap<Url, http::HttpResponse> = HashMap::new();
+
+    println!("\nDownloading images:");
+    // Spawn a progress bar showing how many images have been downloaded.
+    let progress_bar = ProgressBar::new(book.image_urls_and_tags.keys().len().try_into().unwrap());
+    progress_bar.set_style(
+        ProgressStyle::with_template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent}%  ")
+            .unwrap()
+            .progress_chars("#>-"),
     );
-    output_file.write_all(buf.as_bytes()).unwrap();
 
-    for chapter in book.chapters {
-        let mut buf;
+    let image_urls: Vec<&Url> = book.image_urls_and_tags.keys().collect();
 
-        if !markdown_args.no_chapter_titles {
-            buf = format!("----\n{}", chapter.chapter_name);
-            output_file.write_all(buf.as_bytes()).unwrap();
-        }
+    misc::parallel_for_each(
+        &image_urls,
+        jobs,
+        |image_url| download_image(fetcher, image_url, &progress_bar),
+        |i, image| {
+            if let Some(image) = image {
+                downloaded_images.insert(image_urls[i].clone(), image);
+            }
+            progress_bar.inc(1);
+        },
+    );
 
-        if markdown_args.no_image_tags {
-            // Remove image tags or not depending on args.
-            buf = format!(
-                "\n\n{}\n\n",
-                html2md::parse_html(&html::remove_image_tags(&chapter.isolated_chapter_html))
-            );
-        } else {
-            buf = format!(
-                "\n\n{}\n\n",
-                html2md::parse_html(&chapter.isolated_chapter_html.html())
-            );
-        }
+    progress_bar.finish();
+
+    downloaded_images
+}
 
-        output_file.write_all(buf.as_bytes()).unwrap();
+/// Download an image.
+///
+/// Returns None & adds a warning if the download failed, so a placeholder can be used instead.
+fn download_image(
+    fetcher: &dyn Fetcher,
+    image_url: &Url,
+    progress_bar: &ProgressBar,
+) -> Option<http::HttpResponse> {
+    match fetcher.fetch(image_url) {
+        Ok(image) => Some(image),
+        Err(error) => {
+            progress_bar.println(format!("Failed to download {image_url}: {error}"));
+            add_image_download_warning(image_url, &error);
+            None
+        },
     }
+}
 
-    Ok(WARNINGS.lock().unwrap())
+/// Add a warning for an image that couldn't be downloaded.
+fn add_image_download_warning(image_url: &Url, error: &GenerationError) {
+    let warning = Warning::ImageDownloadError {
+        warning_msg: "Unable to download image, using a placeholder".to_string(),
+        url: image_url.clone(),
+        error: error.to_string(),
+    };
+    WARNINGS.lock().unwrap().add_warning(warning);
+}
+
+/// Apply the download arguments & set up the fetcher & run state before any requests are made.
+///
+/// Formats that keep a record cache their downloads next to it, so a later update only downloads what changed.
+fn setup_download(
+    download_args: &DownloadArgs,
+    book_url: &Url,
+    output_directory: &Path,
+    keeps_record: bool,
+) -> Result<(Box<dyn Fetcher>, RunState), GenerationError> {
+    let run_state = RunState::new(book_url, output_directory, download_args.resume)?;
+
+    let fetcher: Box<dyn Fetcher> = match &download_args.fixture_directory {
+        // Fixtures are already on disk, so there's nothing to cache.
+        Some(fixture_directory) => Box::new(FixtureFetcher {
+            directory: fixture_directory.clone(),
+        }),
+        None => {
+            // Without a cache directory the downloads get cached next to the record, or in the run state,
+            // so they survive an interrupted run.
+            let cache = match &download_args.cache_directory {
+                Some(cache_directory) => cache::Cache::new(cache_directory.clone())?,
+                None if keeps_record => {
+                    cache::Cache::new(BookRecord::cache_directory(output_directory))?
+                },
+                None => run_state.default_cache()?,
+            };
+
+            Box::new(ReqwestFetcher::new(
+                http::RequestPolicy {
+                    request_delay: Duration::from_millis(download_args.request_delay),
+                    max_retries: download_args.max_retries,
+                    retry_backoff: Duration::from_millis(download_args.retry_backoff),
+                    timeout: Duration::from_secs(download_args.timeout),
+                },
+                Some(cache),
+            ))
+        },
+    };
+
+    Ok((fetcher, run_state))
 }
 
 /// An error struct representing all the documented errors that can occur while archiving a RoyalRoad webnovel.
@@ -351,10 +2048,67 @@ pub enum GenerationError {
         file_path: PathBuf,
     },
 
+    /// Represents errors during directory creation.
+    #[error("Unable to create directory: {directory_path}\n{error}")]
+    DirectoryCreationError {
+        error: std::io::Error,
+        directory_path: PathBuf,
+    },
+
+    /// Represents errors during directory deletion.
+    #[error("Unable to delete directory: {directory_path}\n{error}")]
+    DirectoryDeletionError {
+        error: std::io::Error,
+        directory_path: PathBuf,
+    },
+
+    /// Represents errors when writing to a file.
+    #[error("Unable to write to file: {file_path}\n{error}")]
+    FileWriteError {
+        error: std::io::Error,
+        file_path: PathBuf,
+    },
+
+    /// Represents errors when reading from a file.
+    #[error("Unable to read file: {file_path}\n{error}")]
+    FileReadError {
+        error: std::io::Error,
+        file_path: PathBuf,
+    },
+
+    /// Represents errors when writing the record of an archival run.
+    #[error("Unable to write the archive record: {file_path}\n{error}")]
+    RecordWriteError {
+        error: serde_json::Error,
+        file_path: PathBuf,
+    },
+
+    /// Represents errors when writing the finished epub.
+    #[error("Unable to write the epub: {error}")]
+    EpubWriteError { error: zip::result::ZipError },
+
     /// Represents errors when getting a Response from a Url.
     #[error("Unable to get response for: {url}\n{error}")]
     ResponseGetError { error: reqwest::Error, url: Url },
 
+    /// Represents errors when the server responds with an error status code.
+    #[error("Got an error response for: {url}\n{status}")]
+    ResponseStatusError {
+        status: reqwest::StatusCode,
+        url: Url,
+    },
+
+    /// Represents errors when a url has no saved page in the fixture directory.
+    #[error("No fixture found for: {url}")]
+    FixtureNotFoundError { url: Url },
+
+    /// Represents errors when reading a saved page from the fixture directory.
+    #[error("Unable to read fixture: {file_path}\n{error}")]
+    FixtureReadError {
+        error: std::io::Error,
+        file_path: PathBuf,
+    },
+
     /// Represents errors when converting a Response to a String.
     #[error("Unable to convert response to text: {error}")]
     ResponseConvertToTextError { error: reqwest::Error },
@@ -370,18 +2124,6 @@ pub enum GenerationError {
         string_url: String,
     },
 
-    /// Represents io errors when trying to create a temporary directory.
-    #[error("Unable to create temporary directory: {error}")]
-    TempDirCreationError { error: std::io::Error },
-
-    /// Represents an error when trying to extract the html2xhtml binaries into the temporary directory.
-    #[error("Unable to extract html2xhtml into the temporary directory: {error}")]
-    Html2XhtmlExtractionError { error: zip_extract::ZipExtractError },
-
-    /// Represents an error when trying to start html2xhtml.
-    #[error("Unable to start html2xhtml: {error}")]
-    Html2XhtmlStartError { error: std::io::Error },
-
     /// Represents an error when trying to find the book title.
     #[error("Unable to fetch the book title for: {url}")]
     BookTitleFetchError { url: Url },
@@ -400,17 +2142,38 @@ pub enum GenerationError {
     #[error("Unable to fetch the chapter names and urls for: {url}")]
     BookChapterNameAndUrlFetchError { url: Url },
 
+    /// Represents an error when the chapter selection doesn't match any chapters.
+    #[error("None of the chapters of {url} match the chapter selection")]
+    NoChaptersSelectedError { url: Url },
+
     /// Represents an error when trying to isolate the chapter content.
     #[error("Unable to isolate chapter content for: {url}")]
     ChapterContentIsolationError { url: Url },
 
-    /// Represents an error for when the target os is unsupported.
-    #[error("{os} is unsupported")]
-    OsUnsupportedError { os: Oses },
+    /// Represents an error when trying to start or communicate with a text-to-speech engine.
+    #[error("Unable to run the text-to-speech engine {engine}: {error}")]
+    TtsStartError {
+        engine: String,
+        error: std::io::Error,
+    },
+
+    /// Represents an error when a text-to-speech engine fails to synthesize the text.
+    #[error("The text-to-speech engine {engine} failed: {status}")]
+    TtsSynthesisError { engine: String, status: String },
+
+    /// Represents an error when a text-to-speech engine needs a voice model that was not given.
+    #[error("The text-to-speech engine {engine} requires a voice model")]
+    TtsModelMissingError { engine: String },
+
+    /// Represents an error when some chapters could not be downloaded.
+    ///
+    /// Everything that was downloaded is kept, so the run can be resumed.
+    #[error("Unable to download {0} chapters.\nRun again with --resume to continue where this run stopped.", failed_chapters.len())]
+    IncompleteDownloadError { failed_chapters: Vec<Url> },
 
-    /// Represents an error that shows the generation method is unsupported.
-    #[error("This generation mode is currently unsupported")]
-    GenerationUnsupportedError,
+    /// Represents an error when the margins & font size of a pdf leave no room for text.
+    #[error("The margins & font size leave no room for text on a {page_size:?} page")]
+    PdfLayoutError { page_size: PageSize },
 }
 
 /// A struct that contains a vector of warnings.
@@ -448,9 +2211,13 @@ pub enum Warning {
     MissingContentType {
         warning_msg: String,
         url: Url,
-        error: ToStrError,
+        error: Option<ToStrError>,
     },
 
+    /// Warning for when the book's index page has no description.
+    #[error("{warning_msg}: {url}")]
+    MissingDescription { warning_msg: String, url: Url },
+
     /// Warning for when a temporary directory is unable to be deleted.
     #[error("{warning_msg}")]
     TempDirDeletionError {
@@ -459,6 +2226,74 @@ pub enum Warning {
         error: std::io::Error,
     },
 
+    /// Warning for when a run couldn't download everything, so its state is kept for resuming it.
+    #[error("{warning_msg}")]
+    IncompleteRun { warning_msg: String },
+
+    /// Warning for when what failed to download in a run can not be recorded in its state.
+    #[error("{warning_msg}: {state_path}\n{error}")]
+    RunStateWriteError {
+        warning_msg: String,
+        state_path: PathBuf,
+        error: String,
+    },
+
+    /// Warning for when the record of a previous run exists but can not be read.
+    #[error("{warning_msg}")]
+    RecordReadError {
+        warning_msg: String,
+        record_path: PathBuf,
+        error: String,
+    },
+
+    /// Warning for when a cached response can not be read.
+    #[error("{warning_msg}: {url}\n{error}")]
+    CacheReadError {
+        warning_msg: String,
+        url: Url,
+        error: String,
+    },
+
+    /// Warning for when a response can not be written to the cache.
+    #[error("{warning_msg}: {url}\n{error}")]
+    CacheWriteError {
+        warning_msg: String,
+        url: Url,
+        error: String,
+    },
+
+    /// Warning for when an image can not be downloaded & a placeholder is used instead.
+    #[error("{warning_msg}: {url}\n{error}")]
+    ImageDownloadError {
+        warning_msg: String,
+        url: Url,
+        error: String,
+    },
+
+    /// Warning for when an image is a type the epub spec does not allow & can not be converted.
+    #[error("{warning_msg}: {url} ({content_type})")]
+    UnsupportedImageType {
+        warning_msg: String,
+        url: Url,
+        content_type: String,
+    },
+
+    /// Warning for when a chapter has characters the pdf fonts can not show, which get replaced with question marks.
+    #[error("{warning_msg}: {chapter_name} ({count} characters)")]
+    UnsupportedPdfCharacters {
+        warning_msg: String,
+        chapter_name: String,
+        count: usize,
+    },
+
+    /// Warning for when an image can not be resized or recompressed.
+    #[error("{warning_msg}: {url}\n{error}")]
+    ImageProcessingError {
+        warning_msg: String,
+        url: Url,
+        error: String,
+    },
+
     /// Warning for when the program can not parse a url in an image tag.
     #[error("{warning_msg}")]
     ImageTagParseError {
@@ -467,3 +2302,189 @@ pub enum Warning {
         error: url::ParseError,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use reqwest::{header::HeaderMap, StatusCode};
+
+    use super::*;
+
+    /// Make a chapter from a page with a pre note, content & post note.
+    fn chapter() -> book::Chapter {
+        let url: Url = "https://www.royalroad.com/fiction/1/test/chapter/11/one"
+            .parse()
+            .unwrap();
+        let page = r#"<html><head><style>.hidden{display:none}</style></head><body>
+            <div class="author-note-portlet"><div class="author-note"><p>Before.</p></div></div>
+            <div class="chapter-inner chapter-content"><p>First line.</p><p class="hidden">Stolen.</p><hr><p>Second line.</p></div>
+            <div class="author-note-portlet"><div class="author-note"><p>After.</p></div></div>
+            </body></html>"#;
+
+        let metadata = book::ChapterMetadata {
+            id: 11,
+            title: "One".to_string(),
+            url: url.clone(),
+            date: None,
+            order: Some(0),
+            volume_id: None,
+        };
+        let page =
+            http::HttpResponse::new(url, StatusCode::OK, HeaderMap::new(), Bytes::from(page));
+
+        book::Chapter::new(metadata, page).unwrap()
+    }
+
+    fn audiobook_args(no_chapter_titles: bool, author_notes: AuthorNotes) -> AudiobookArgs {
+        AudiobookArgs {
+            no_chapter_titles,
+            split_novel_by_chapters: false,
+            tts_engine: TtsEngine::EspeakNg,
+            voice: None,
+            piper_model: None,
+            author_notes,
+        }
+    }
+
+    #[test]
+    fn chapter_speech_text_with_title_and_notes() {
+        assert_eq!(
+            chapter_speech_text(&chapter(), &audiobook_args(false, AuthorNotes::Inline)),
+            "One\n\nBefore.\n\nFirst line.\n\nSecond line.\n\nAfter."
+        );
+    }
+
+    #[test]
+    fn chapter_speech_text_without_title_or_notes() {
+        assert_eq!(
+            chapter_speech_text(&chapter(), &audiobook_args(true, AuthorNotes::Omit)),
+            "First line.\n\nSecond line."
+        );
+        // Notes going in the appendix aren't spoken with the chapter.
+        assert_eq!(
+            chapter_speech_text(&chapter(), &audiobook_args(true, AuthorNotes::Appendix)),
+            "First line.\n\nSecond line."
+        );
+    }
+
+    /// Make an image response from the given bytes, without a content-type so it gets sniffed.
+    fn image_response(bytes: Vec<u8>) -> http::HttpResponse {
+        http::HttpResponse::new(
+            "https://i.imgur.com/a.webp".parse().unwrap(),
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::from(bytes),
+        )
+    }
+
+    #[test]
+    fn process_image_converts_webp() {
+        let mut webp = Vec::new();
+        image::DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut Cursor::new(&mut webp), image::ImageFormat::WebP)
+            .unwrap();
+        let image_args = ImageArgs {
+            webp_to_jpeg: true,
+            ..Default::default()
+        };
+
+        let (bytes, content_type, file_extension) = process_image(
+            &"https://i.imgur.com/a.webp".parse().unwrap(),
+            &image_response(webp),
+            &image_args,
+        );
+
+        assert!(bytes.starts_with(&[0xFF, 0xD8, 0xFF]));
+        assert_eq!(
+            (content_type.as_str(), file_extension.as_str()),
+            ("image/jpeg", "jpeg")
+        );
+    }
+
+    #[test]
+    fn process_image_keeps_the_type_of_images_that_fail_to_convert() {
+        // Looks like a webp, but can't be decoded.
+        let broken_webp = b"RIFF\x10\0\0\0WEBPVP8 broken!!".to_vec();
+        let image_args = ImageArgs {
+            webp_to_jpeg: true,
+            ..Default::default()
+        };
+
+        let (bytes, content_type, file_extension) = process_image(
+            &"https://i.imgur.com/a.webp".parse().unwrap(),
+            &image_response(broken_webp.clone()),
+            &image_args,
+        );
+
+        assert_eq!(bytes, broken_webp);
+        assert_eq!(
+            (content_type.as_str(), file_extension.as_str()),
+            ("image/webp", "webp")
+        );
+    }
+
+    fn txt_args(
+        wrap_width: Option<u16>,
+        line_endings: LineEndings,
+        normalization: Option<Normalization>,
+    ) -> TxtArgs {
+        TxtArgs {
+            no_chapter_titles: false,
+            update: false,
+            chapter_dates: false,
+            author_notes: AuthorNotes::Inline,
+            wrap_width,
+            line_endings,
+            normalization,
+        }
+    }
+
+    #[test]
+    fn format_text_unchanged_by_default() {
+        let text = "A line.\n\nAnother line that is fairly long.".to_string();
+        assert_eq!(
+            format_text(text.clone(), &txt_args(None, LineEndings::Lf, None)),
+            text
+        );
+    }
+
+    #[test]
+    fn format_text_wraps_at_spaces_only() {
+        let text = "one two three-four https://example.com/a/long/url five".to_string();
+        assert_eq!(
+            format_text(text, &txt_args(Some(10), LineEndings::Lf, None)),
+            "one two\nthree-four\nhttps://example.com/a/long/url\nfive"
+        );
+    }
+
+    #[test]
+    fn format_text_keeps_paragraph_breaks_when_wrapping() {
+        let text = "aaa bbb ccc\n\nddd".to_string();
+        assert_eq!(
+            format_text(text, &txt_args(Some(7), LineEndings::Crlf, None)),
+            "aaa bbb\r\nccc\r\n\r\nddd"
+        );
+    }
+
+    #[test]
+    fn format_text_normalizes_before_wrapping() {
+        // The ligature & fullwidth letters become plain ones with nfkc.
+        let text = "ﬁne ＡＢＣ".to_string();
+        assert_eq!(
+            format_text(
+                text,
+                &txt_args(None, LineEndings::Lf, Some(Normalization::Nfkc))
+            ),
+            "fine ABC"
+        );
+
+        let text = "e\u{301}".to_string();
+        assert_eq!(
+            format_text(
+                text,
+                &txt_args(None, LineEndings::Lf, Some(Normalization::Nfc))
+            ),
+            "\u{e9}"
+        );
+    }
+}
@@ -1,4 +1,11 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
 
 /// An extension to ``std::collections::HashMap<K, Vec<String>>``
 pub trait HashMapExt<K> {
@@ -31,22 +38,45 @@ impl<K: std::cmp::Eq + std::hash::Hash + std::clone::Clone> HashMapExt<K>
             }
         }
 
-        return self;
+        self
     }
 }
 
-/// A list of Oses for error handling purposes.
-#[derive(Debug)]
-pub enum Oses {
-    Windows,
-    Linux,
-    MacOs,
-    OtherUnknownOs,
-}
+/// Run ``work`` on every item using up to ``jobs`` worker threads.
+///
+/// ``on_result`` is called on the calling thread with the index of each item & its result as soon as it's ready.
+/// Results can arrive in any order, so use the index to put them back in place.
+pub fn parallel_for_each<T, R>(
+    items: &[T],
+    jobs: usize,
+    work: impl Fn(&T) -> R + Sync,
+    mut on_result: impl FnMut(usize, R),
+) where
+    T: Sync,
+    R: Send,
+{
+    let next_item = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
 
-/// Implement display for Oses.
-impl Display for Oses {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            let sender = sender.clone();
+            let next_item = &next_item;
+            let work = &work;
+
+            scope.spawn(move || loop {
+                let i = next_item.fetch_add(1, Ordering::Relaxed);
+                if i >= items.len() || sender.send((i, work(&items[i]))).is_err() {
+                    break;
+                }
+            });
+        }
+
+        // Drop the original sender so the receiver stops once every worker is done.
+        drop(sender);
+
+        for (i, result) in receiver {
+            on_result(i, result);
+        }
+    });
 }
@@ -0,0 +1,1420 @@
+use std::{collections::HashMap, io::Cursor};
+
+use ego_tree::NodeRef;
+use image::{ExtendedColorType, ImageDecoder, ImageFormat, ImageReader};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use pdf_writer::{
+    types::{ActionType, AnnotationType, PageMode},
+    Content, Filter, Finish, Name, Pdf, Rect, Ref, Str, TextStr,
+};
+use scraper::{Html, Node};
+use url::Url;
+
+use crate::{
+    book::{Book, Chapter},
+    html,
+    http::HttpResponse,
+    AuthorNotes, PageSize, PdfArgs, Warning, WARNINGS,
+};
+
+/// Points per millimetre.
+const POINTS_PER_MM: f32 = 72.0 / 25.4;
+
+/// How tall a line is compared to the font size.
+const LINE_HEIGHT: f32 = 1.4;
+
+/// Images are shown at 96 dpi (0.75 points per pixel), unless they're too big for the page.
+const POINTS_PER_PIXEL: f32 = 0.75;
+
+/// Widths of the printable ascii characters (32 to 126) in Helvetica, in 1/1000ths of the font size.
+/// Taken from the Adobe font metrics (AFM) files for the standard 14 fonts.
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+/// Widths of the printable ascii characters (32 to 126) in Helvetica-Bold, in 1/1000ths of the font size.
+const HELVETICA_BOLD_WIDTHS: [u16; 95] = [
+    278, 333, 474, 556, 556, 889, 722, 238, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 333, 333, 584, 584, 584, 611, 975, 722, 722, 722, 722, 667,
+    611, 778, 722, 278, 556, 722, 611, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 333, 278, 333, 584, 556, 333, 556, 611, 556, 611, 556, 333, 611, 611, 278, 278, 556,
+    278, 889, 611, 611, 611, 611, 389, 556, 333, 611, 556, 778, 556, 556, 500, 389, 280, 389, 584,
+];
+
+/// The fonts used in the pdf. They're all standard fonts, so nothing has to be embedded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Font {
+    Regular,
+    Bold,
+    Italic,
+    BoldItalic,
+}
+
+impl Font {
+    const ALL: [Font; 4] = [Font::Regular, Font::Bold, Font::Italic, Font::BoldItalic];
+
+    /// The name the font is referred to by in the page resources.
+    fn resource_name(self) -> &'static [u8] {
+        match self {
+            Font::Regular => b"F1",
+            Font::Bold => b"F2",
+            Font::Italic => b"F3",
+            Font::BoldItalic => b"F4",
+        }
+    }
+
+    /// The name of the standard font.
+    fn base_font(self) -> &'static [u8] {
+        match self {
+            Font::Regular => b"Helvetica",
+            Font::Bold => b"Helvetica-Bold",
+            Font::Italic => b"Helvetica-Oblique",
+            Font::BoldItalic => b"Helvetica-BoldOblique",
+        }
+    }
+
+    fn new(bold: bool, italic: bool) -> Font {
+        match (bold, italic) {
+            (false, false) => Font::Regular,
+            (true, false) => Font::Bold,
+            (false, true) => Font::Italic,
+            (true, true) => Font::BoldItalic,
+        }
+    }
+
+    fn is_bold(self) -> bool {
+        matches!(self, Font::Bold | Font::BoldItalic)
+    }
+
+    /// The width of some text at the given font size, in points.
+    fn text_width(self, text: &str, font_size: f32) -> f32 {
+        text.chars()
+            .map(|char| self.char_width(char) as f32)
+            .sum::<f32>()
+            * font_size
+            / 1000.0
+    }
+
+    /// The width of a character in 1/1000ths of the font size.
+    ///
+    /// Only ascii & the common punctuation have exact widths, everything else is a close enough guess.
+    fn char_width(self, char: char) -> u16 {
+        let widths = if self.is_bold() {
+            &HELVETICA_BOLD_WIDTHS
+        } else {
+            &HELVETICA_WIDTHS
+        };
+
+        match encode_char(char) {
+            code @ 32..=126 => widths[code as usize - 32],
+            // Curly single quotes.
+            0x91 | 0x92 => widths[(b'\'' - 32) as usize] + 31,
+            // Curly double quotes.
+            0x93 | 0x94 => widths[(b'"' - 32) as usize] - 22,
+            // Bullet.
+            0x95 => 350,
+            // En dash.
+            0x96 => 556,
+            // Em dash, ellipsis & per mille.
+            0x97 | 0x85 | 0x89 => 1000,
+            // Non breaking space.
+            0xA0 => 278,
+            _ => 556,
+        }
+    }
+}
+
+/// Encode a character in WinAnsiEncoding, the encoding used by the standard fonts.
+///
+/// Characters that can't be encoded become a question mark.
+fn encode_char(char: char) -> u8 {
+    match char {
+        ' '..='~' | '\u{A0}'..='\u{FF}' => char as u8,
+        '€' => 0x80,
+        '‚' => 0x82,
+        'ƒ' => 0x83,
+        '„' => 0x84,
+        '…' => 0x85,
+        '†' => 0x86,
+        '‡' => 0x87,
+        'ˆ' => 0x88,
+        '‰' => 0x89,
+        'Š' => 0x8A,
+        '‹' => 0x8B,
+        'Œ' => 0x8C,
+        'Ž' => 0x8E,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        '˜' => 0x98,
+        '™' => 0x99,
+        'š' => 0x9A,
+        '›' => 0x9B,
+        'œ' => 0x9C,
+        'ž' => 0x9E,
+        'Ÿ' => 0x9F,
+        _ => b'?',
+    }
+}
+
+/// Encode a string in WinAnsiEncoding.
+fn encode_text(text: &str) -> Vec<u8> {
+    text.chars().map(encode_char).collect()
+}
+
+/// Count the characters in a string that can't be encoded in WinAnsiEncoding & will show up as question marks.
+fn count_unsupported_chars(text: &str) -> usize {
+    text.chars()
+        .filter(|char| !char.is_whitespace() && *char != '?' && encode_char(*char) == b'?')
+        .count()
+}
+
+/// A piece of a paragraph, before it's broken into lines.
+#[derive(Clone, Debug)]
+enum Piece {
+    Word(String, Font),
+    Space,
+    Break,
+}
+
+/// Something on its own line(s) in the pdf.
+#[derive(Debug)]
+enum Block {
+    Paragraph(Vec<Piece>),
+    Image { url: Url, alt_text: Option<String> },
+    Rule,
+}
+
+/// How a paragraph is lined up.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Center,
+}
+
+/// Convert html into the blocks that get laid out in the pdf.
+fn html_to_blocks(html: &Html) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut paragraph: Vec<Piece> = Vec::new();
+
+    collect_blocks(html.tree.root(), Font::Regular, &mut blocks, &mut paragraph);
+    push_paragraph(&mut blocks, &mut paragraph);
+
+    blocks
+}
+
+/// Recursively walk the html tree collecting the blocks.
+fn collect_blocks(
+    node: NodeRef<Node>,
+    font: Font,
+    blocks: &mut Vec<Block>,
+    paragraph: &mut Vec<Piece>,
+) {
+    const BLOCK_ELEMENTS: [&str; 24] = [
+        "address",
+        "article",
+        "aside",
+        "blockquote",
+        "dd",
+        "div",
+        "dl",
+        "dt",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "li",
+        "ol",
+        "p",
+        "pre",
+        "section",
+        "table",
+        "tbody",
+        "thead",
+        "tr",
+        "ul",
+    ];
+
+    for child in node.children() {
+        match child.value() {
+            Node::Text(text) => push_text(paragraph, text, font),
+            Node::Element(element) => {
+                let name = element.name();
+                match name {
+                    "script" | "style" => continue,
+                    "br" => {
+                        paragraph.push(Piece::Break);
+                        continue;
+                    },
+                    "hr" => {
+                        push_paragraph(blocks, paragraph);
+                        blocks.push(Block::Rule);
+                        continue;
+                    },
+                    "img" => {
+                        push_paragraph(blocks, paragraph);
+                        if let Some(url) = element.attr("src").and_then(|src| Url::parse(src).ok())
+                        {
+                            blocks.push(Block::Image {
+                                url,
+                                alt_text: element.attr("alt").map(str::to_string),
+                            });
+                        }
+                        continue;
+                    },
+                    _ => (),
+                }
+
+                let child_font = match name {
+                    "b" | "strong" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => {
+                        Font::new(true, matches!(font, Font::Italic | Font::BoldItalic))
+                    },
+                    "i" | "em" | "cite" => Font::new(font.is_bold(), true),
+                    _ if element.has_class(
+                        "author-note",
+                        scraper::CaseSensitivity::AsciiCaseInsensitive,
+                    ) =>
+                    {
+                        Font::new(font.is_bold(), true)
+                    },
+                    _ => font,
+                };
+
+                let is_block = BLOCK_ELEMENTS.contains(&name);
+                if is_block {
+                    push_paragraph(blocks, paragraph);
+                }
+                if name == "li" {
+                    paragraph.push(Piece::Word("•".to_string(), font));
+                    paragraph.push(Piece::Space);
+                }
+
+                collect_blocks(child, child_font, blocks, paragraph);
+
+                // The cells of a row stay on one line, spaced apart.
+                if is_block {
+                    push_paragraph(blocks, paragraph);
+                } else if matches!(name, "td" | "th") {
+                    paragraph.push(Piece::Space);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Add text to a paragraph, collapsing whitespace.
+fn push_text(paragraph: &mut Vec<Piece>, text: &str, font: Font) {
+    for char in text.chars() {
+        if char.is_whitespace() {
+            if !matches!(paragraph.last(), Some(Piece::Space)) {
+                paragraph.push(Piece::Space);
+            }
+            continue;
+        }
+
+        match paragraph.last_mut() {
+            Some(Piece::Word(word, word_font)) if *word_font == font => word.push(char),
+            _ => paragraph.push(Piece::Word(char.to_string(), font)),
+        }
+    }
+}
+
+/// Finish the current paragraph, trimming whitespace & dropping it if it's empty.
+fn push_paragraph(blocks: &mut Vec<Block>, paragraph: &mut Vec<Piece>) {
+    while matches!(paragraph.last(), Some(Piece::Space | Piece::Break)) {
+        paragraph.pop();
+    }
+    let start = paragraph
+        .iter()
+        .position(|piece| matches!(piece, Piece::Word(..)))
+        .unwrap_or(paragraph.len());
+
+    if start < paragraph.len() {
+        blocks.push(Block::Paragraph(paragraph.split_off(start)));
+    }
+    paragraph.clear();
+}
+
+/// Turn plain text into a paragraph in a single font.
+fn text_to_pieces(text: &str, font: Font) -> Vec<Piece> {
+    let mut pieces: Vec<Piece> = Vec::new();
+    push_text(&mut pieces, text, font);
+    pieces
+}
+
+/// A line of text, made up of runs of text in one font.
+#[derive(Default)]
+struct Line {
+    /// The x offset, text & font of each run.
+    runs: Vec<(f32, String, Font)>,
+    width: f32,
+}
+
+/// Break a paragraph into lines no wider than the max width.
+///
+/// Words that are too long to fit on a line by themselves (E.G. urls) get split wherever they run out of room.
+fn break_lines(pieces: &[Piece], font_size: f32, max_width: f32) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut line = Line::default();
+    let mut pending_space = false;
+
+    let mut i = 0;
+    while i < pieces.len() {
+        match &pieces[i] {
+            Piece::Break => {
+                lines.push(std::mem::take(&mut line));
+                pending_space = false;
+                i += 1;
+            },
+            Piece::Space => {
+                pending_space = !line.runs.is_empty();
+                i += 1;
+            },
+            Piece::Word(..) => {
+                // Words in different fonts without a space in between can't be split up.
+                let mut word: Vec<(&str, Font)> = Vec::new();
+                while let Some(Piece::Word(text, font)) = pieces.get(i) {
+                    word.push((text, *font));
+                    i += 1;
+                }
+                let word_width: f32 = word
+                    .iter()
+                    .map(|(text, font)| font.text_width(text, font_size))
+                    .sum();
+
+                let space_width = match (pending_space, line.runs.last()) {
+                    (true, Some((_, _, font))) => font.text_width(" ", font_size),
+                    _ => 0.0,
+                };
+                pending_space = false;
+
+                if !line.runs.is_empty() && line.width + space_width + word_width > max_width {
+                    lines.push(std::mem::take(&mut line));
+                    push_word(&mut line, &word, font_size, false);
+                } else {
+                    push_word(&mut line, &word, font_size, space_width > 0.0);
+                }
+
+                if line.width > max_width {
+                    split_line(&mut lines, &mut line, font_size, max_width);
+                }
+            },
+        }
+    }
+
+    if !line.runs.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Add a word to the end of a line, joining it onto the last run if it's in the same font.
+fn push_word(line: &mut Line, word: &[(&str, Font)], font_size: f32, with_space: bool) {
+    for (i, (text, font)) in word.iter().enumerate() {
+        let space = if i == 0 && with_space { " " } else { "" };
+
+        match line.runs.last_mut() {
+            Some((_, run_text, run_font)) if run_font == font => {
+                run_text.push_str(space);
+                run_text.push_str(text);
+            },
+            _ => {
+                if !space.is_empty() {
+                    line.width += font.text_width(space, font_size);
+                }
+                line.runs.push((line.width, text.to_string(), *font));
+                line.width += font.text_width(text, font_size);
+                continue;
+            },
+        }
+
+        line.width += font.text_width(&format!("{space}{text}"), font_size);
+    }
+}
+
+/// Split a line that's too wide into as many lines as it takes, one character at a time.
+fn split_line(lines: &mut Vec<Line>, line: &mut Line, font_size: f32, max_width: f32) {
+    let runs = std::mem::take(&mut line.runs);
+    line.width = 0.0;
+
+    for (_, text, font) in runs {
+        for char in text.chars() {
+            let char_width = font.char_width(char) as f32 * font_size / 1000.0;
+            if !line.runs.is_empty() && line.width + char_width > max_width {
+                lines.push(std::mem::take(line));
+            }
+
+            match line.runs.last_mut() {
+                Some((_, run_text, run_font)) if *run_font == font => run_text.push(char),
+                _ => line.runs.push((line.width, char.to_string(), font)),
+            }
+            line.width += char_width;
+        }
+    }
+}
+
+/// Where a link goes.
+enum LinkTarget {
+    Page(usize),
+    Uri(String),
+}
+
+/// A clickable area on a page.
+struct Link {
+    rect: Rect,
+    target: LinkTarget,
+}
+
+/// A page that's been laid out but not written yet.
+struct Page {
+    content: Content,
+    images: Vec<usize>,
+    links: Vec<Link>,
+}
+
+/// An image ready to be embedded in the pdf.
+struct EmbeddedImage {
+    data: Vec<u8>,
+    filter: Filter,
+    width: u32,
+    height: u32,
+    grayscale: bool,
+    /// The compressed transparency of the image, if it has any.
+    alpha: Option<Vec<u8>>,
+}
+
+/// An entry in the table of contents & the pdf outline.
+struct TocEntry {
+    title: String,
+    /// 0 for volumes & chapters not in a volume, 1 for chapters in a volume.
+    level: usize,
+    page: usize,
+}
+
+/// Lays out text & images onto pages.
+struct Layout {
+    page_width: f32,
+    page_height: f32,
+    margin: f32,
+    font_size: f32,
+    pages: Vec<Page>,
+    /// How far down the current page the next thing goes, measured from the top.
+    y: f32,
+}
+
+impl Layout {
+    fn new(page_width: f32, page_height: f32, margin: f32, font_size: f32) -> Self {
+        Layout {
+            page_width,
+            page_height,
+            margin,
+            font_size,
+            pages: Vec::new(),
+            y: 0.0,
+        }
+    }
+
+    fn content_width(&self) -> f32 {
+        self.page_width - 2.0 * self.margin
+    }
+
+    fn content_height(&self) -> f32 {
+        self.page_height - 2.0 * self.margin
+    }
+
+    fn new_page(&mut self) {
+        self.pages.push(Page {
+            content: Content::new(),
+            images: Vec::new(),
+            links: Vec::new(),
+        });
+        self.y = self.margin;
+    }
+
+    /// Start a new page if there isn't enough room left on this one.
+    fn ensure_space(&mut self, height: f32) {
+        let at_page_top = self.y <= self.margin;
+        if self.pages.is_empty()
+            || (!at_page_top && self.y + height > self.page_height - self.margin)
+        {
+            self.new_page();
+        }
+    }
+
+    /// Add some vertical space, unless it's at the top of a page.
+    fn add_space(&mut self, height: f32) {
+        if self.y > self.margin {
+            self.y += height;
+        }
+    }
+
+    fn page(&mut self) -> &mut Page {
+        if self.pages.is_empty() {
+            self.new_page();
+        }
+        self.pages.last_mut().unwrap()
+    }
+
+    /// Lay out a paragraph, returning the area each line took up.
+    fn add_paragraph(&mut self, pieces: &[Piece], font_size: f32, align: Align) -> Vec<Rect> {
+        let line_height = font_size * LINE_HEIGHT;
+        let mut line_rects: Vec<Rect> = Vec::new();
+
+        for line in break_lines(pieces, font_size, self.content_width()) {
+            self.ensure_space(line_height);
+
+            let x_offset = match align {
+                Align::Left => self.margin,
+                Align::Center => self.margin + (self.content_width() - line.width) / 2.0,
+            };
+            let baseline = self.page_height - self.y - (line_height + font_size * 0.7) / 2.0;
+
+            let content = &mut self.page().content;
+            content.begin_text();
+            for (x, text, font) in &line.runs {
+                content
+                    .set_font(Name(font.resource_name()), font_size)
+                    .set_text_matrix([1.0, 0.0, 0.0, 1.0, x_offset + x, baseline])
+                    .show(Str(&encode_text(text)));
+            }
+            content.end_text();
+
+            let top = self.page_height - self.y;
+            line_rects.push(Rect::new(
+                x_offset,
+                top - line_height,
+                x_offset + line.width,
+                top,
+            ));
+            self.y += line_height;
+        }
+
+        line_rects
+    }
+
+    /// Lay out text in a single font.
+    fn add_text(&mut self, text: &str, font: Font, font_size: f32, align: Align) -> Vec<Rect> {
+        self.add_paragraph(&text_to_pieces(text, font), font_size, align)
+    }
+
+    /// Lay out an image, scaled down to fit in the given fraction of the page height if needed.
+    fn add_image(&mut self, image_index: usize, image: &EmbeddedImage, max_height_fraction: f32) {
+        let mut width = image.width as f32 * POINTS_PER_PIXEL;
+        let mut height = image.height as f32 * POINTS_PER_PIXEL;
+
+        let scale = (self.content_width() / width)
+            .min(self.content_height() * max_height_fraction / height)
+            .min(1.0);
+        width *= scale;
+        height *= scale;
+
+        self.ensure_space(height);
+
+        let x = self.margin + (self.content_width() - width) / 2.0;
+        let y = self.page_height - self.y - height;
+        let page = self.page();
+        page.content
+            .save_state()
+            .transform([width, 0.0, 0.0, height, x, y])
+            .x_object(Name(format!("Im{image_index}").as_bytes()))
+            .restore_state();
+        if !page.images.contains(&image_index) {
+            page.images.push(image_index);
+        }
+
+        self.y += height;
+    }
+
+    /// Lay out a horizontal line, used for scene breaks.
+    fn add_rule(&mut self) {
+        let height = self.font_size * LINE_HEIGHT;
+        self.ensure_space(height);
+
+        let y = self.page_height - self.y - height / 2.0;
+        let x_start = self.margin + self.content_width() / 4.0;
+        let x_end = self.margin + self.content_width() * 3.0 / 4.0;
+        self.page()
+            .content
+            .set_stroke_gray(0.5)
+            .set_line_width(0.5)
+            .move_to(x_start, y)
+            .line_to(x_end, y)
+            .stroke();
+
+        self.y += height;
+    }
+
+    /// Add a page number to the bottom of every page but the first.
+    fn add_page_numbers(&mut self) {
+        let font_size = self.font_size * 0.8;
+        let y = self.margin / 2.0;
+
+        for (i, page) in self.pages.iter_mut().enumerate().skip(1) {
+            let number = (i + 1).to_string();
+            let x = (self.page_width - Font::Regular.text_width(&number, font_size)) / 2.0;
+
+            page.content
+                .begin_text()
+                .set_font(Name(Font::Regular.resource_name()), font_size)
+                .set_text_matrix([1.0, 0.0, 0.0, 1.0, x, y])
+                .show(Str(number.as_bytes()))
+                .end_text();
+        }
+    }
+}
+
+/// Get the size of a page in points.
+fn page_dimensions(page_size: PageSize) -> (f32, f32) {
+    match page_size {
+        PageSize::A4 => (210.0 * POINTS_PER_MM, 297.0 * POINTS_PER_MM),
+        PageSize::A5 => (148.0 * POINTS_PER_MM, 210.0 * POINTS_PER_MM),
+        PageSize::Letter => (612.0, 792.0),
+        // The screen of a 6" e-reader, 3.6" x 4.8".
+        PageSize::Ereader6 => (259.2, 345.6),
+    }
+}
+
+/// Whether the margins & font size leave enough room on the page for text.
+pub fn layout_fits(pdf_args: &PdfArgs) -> bool {
+    let (page_width, page_height) = page_dimensions(pdf_args.page_size);
+    let margin = pdf_args.margin * POINTS_PER_MM;
+
+    pdf_args.font_size > 0.0
+        && pdf_args.margin >= 0.0
+        && page_width - 2.0 * margin >= pdf_args.font_size * 10.0
+        && page_height - 2.0 * margin >= pdf_args.font_size * LINE_HEIGHT * 10.0
+}
+
+/// Prepare an image for embedding. Jpegs are embedded as they are, everything else is decoded & compressed.
+///
+/// Returns None (after adding a warning) if the image can't be decoded, E.G. it's an svg.
+fn embed_image(image_url: &Url, image_bytes: Vec<u8>) -> Option<EmbeddedImage> {
+    let result = match image::guess_format(&image_bytes) {
+        Ok(ImageFormat::Jpeg) => embed_jpeg(image_bytes),
+        _ => embed_decoded_image(&image_bytes),
+    };
+
+    match result {
+        Ok(image) => Some(image),
+        Err(error) => {
+            let warning = Warning::ImageProcessingError {
+                warning_msg: "Unable to add image to the pdf, using a placeholder".to_string(),
+                url: image_url.clone(),
+                error: error.to_string(),
+            };
+            WARNINGS.lock().unwrap().add_warning(warning);
+
+            None
+        },
+    }
+}
+
+/// Embed a jpeg without decoding it. Cmyk jpegs get decoded, since they'd need their colors inverted.
+fn embed_jpeg(image_bytes: Vec<u8>) -> Result<EmbeddedImage, image::ImageError> {
+    let decoder =
+        ImageReader::with_format(Cursor::new(&image_bytes), ImageFormat::Jpeg).into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    let color_type = decoder.original_color_type();
+    drop(decoder);
+
+    let grayscale = match color_type {
+        ExtendedColorType::L8 => true,
+        ExtendedColorType::Rgb8 => false,
+        _ => return embed_decoded_image(&image_bytes),
+    };
+
+    Ok(EmbeddedImage {
+        data: image_bytes,
+        filter: Filter::DctDecode,
+        width,
+        height,
+        grayscale,
+        alpha: None,
+    })
+}
+
+/// Decode an image & compress its pixels.
+fn embed_decoded_image(image_bytes: &[u8]) -> Result<EmbeddedImage, image::ImageError> {
+    let image = image::load_from_memory(image_bytes)?;
+    let grayscale = !image.color().has_color();
+
+    let alpha = image.color().has_alpha().then(|| {
+        compress_to_vec_zlib(
+            image
+                .to_luma_alpha8()
+                .pixels()
+                .map(|pixel| pixel[1])
+                .collect::<Vec<u8>>()
+                .as_slice(),
+            6,
+        )
+    });
+    let pixels = if grayscale {
+        image.to_luma8().into_raw()
+    } else {
+        image.to_rgb8().into_raw()
+    };
+
+    Ok(EmbeddedImage {
+        data: compress_to_vec_zlib(&pixels, 6),
+        filter: Filter::FlateDecode,
+        width: image.width(),
+        height: image.height(),
+        grayscale,
+        alpha,
+    })
+}
+
+/// Embeds each image once, no matter how many times it's used.
+struct ImageEmbedder<'a> {
+    downloaded_images: &'a HashMap<Url, HttpResponse>,
+    pdf_args: &'a PdfArgs,
+    images: Vec<EmbeddedImage>,
+    /// Maps the image urls to their index in the images, or None if they couldn't be embedded.
+    url_indexes: HashMap<Url, Option<usize>>,
+    /// Maps the image content hashes to their index in the images.
+    hash_indexes: HashMap<String, usize>,
+}
+
+impl ImageEmbedder<'_> {
+    /// Get the index of the embedded image for a url, embedding it if it hasn't been used yet.
+    fn embed(&mut self, image_url: &Url) -> Option<usize> {
+        if let Some(index) = self.url_indexes.get(image_url) {
+            return *index;
+        }
+
+        let index = self.embed_new(image_url);
+        self.url_indexes.insert(image_url.clone(), index);
+        index
+    }
+
+    fn embed_new(&mut self, image_url: &Url) -> Option<usize> {
+        // The images were downloaded before the pdf was laid out, the ones that failed are missing.
+        let image = self.downloaded_images.get(image_url)?;
+        let content_hash = image.content_hash();
+        if let Some(index) = self.hash_indexes.get(&content_hash) {
+            return Some(*index);
+        }
+
+        let image_bytes = if self.pdf_args.image_args.is_enabled() {
+            crate::process_image(image_url, image, &self.pdf_args.image_args).0
+        } else {
+            image.get_bytes().to_vec()
+        };
+
+        let embedded_image = embed_image(image_url, image_bytes)?;
+        self.images.push(embedded_image);
+        self.hash_indexes
+            .insert(content_hash, self.images.len() - 1);
+
+        Some(self.images.len() - 1)
+    }
+}
+
+/// Lay out the blocks of some html, replacing images that can't be shown with a placeholder.
+fn layout_html(layout: &mut Layout, html: &Html, image_embedder: &mut ImageEmbedder) {
+    let font_size = layout.font_size;
+
+    for block in html_to_blocks(html) {
+        match block {
+            Block::Paragraph(pieces) => {
+                layout.add_paragraph(&pieces, font_size, Align::Left);
+            },
+            Block::Rule => layout.add_rule(),
+            Block::Image { url, alt_text } => {
+                let image_index = if image_embedder.pdf_args.no_images {
+                    None
+                } else {
+                    image_embedder.embed(&url)
+                };
+
+                match image_index {
+                    Some(image_index) => {
+                        layout.add_image(image_index, &image_embedder.images[image_index], 0.9)
+                    },
+                    None if image_embedder.pdf_args.no_images => (),
+                    None => {
+                        let placeholder = match alt_text {
+                            Some(alt_text) if !alt_text.trim().is_empty() => {
+                                format!("[Image unavailable: {alt_text}] {url}")
+                            },
+                            _ => format!("[Image unavailable] {url}"),
+                        };
+                        layout.add_text(&placeholder, Font::Italic, font_size, Align::Center);
+                    },
+                }
+            },
+        }
+
+        layout.add_space(font_size * 0.5);
+    }
+}
+
+/// Lay out a chapter, starting on a new page.
+fn layout_chapter(
+    layout: &mut Layout,
+    chapter: &Chapter,
+    pdf_args: &PdfArgs,
+    image_embedder: &mut ImageEmbedder,
+) {
+    let heading = chapter.heading(pdf_args.chapter_dates);
+    let html = chapter.html_with_author_notes(pdf_args.author_notes);
+
+    // The standard fonts only cover WinAnsiEncoding, so let the user know when characters get lost.
+    let unsupported_chars = count_unsupported_chars(&heading)
+        + html
+            .root_element()
+            .text()
+            .map(count_unsupported_chars)
+            .sum::<usize>();
+    if unsupported_chars > 0 {
+        let warning = Warning::UnsupportedPdfCharacters {
+            warning_msg:
+                "Some characters can't be shown by the pdf's fonts & were replaced with '?'"
+                    .to_string(),
+            chapter_name: chapter.chapter_name.clone(),
+            count: unsupported_chars,
+        };
+        WARNINGS.lock().unwrap().add_warning(warning);
+    }
+
+    layout.new_page();
+    layout.add_text(&heading, Font::Bold, layout.font_size * 1.6, Align::Left);
+    layout.add_space(layout.font_size);
+
+    layout_html(layout, &html, image_embedder);
+}
+
+/// Lay out the cover page: the cover image, title, author & info about the book.
+fn layout_cover(
+    layout: &mut Layout,
+    book: &Book,
+    cover_image: Option<&EmbeddedImage>,
+    cover_image_index: Option<usize>,
+) {
+    let font_size = layout.font_size;
+    layout.new_page();
+
+    if let (Some(cover_image), Some(cover_image_index)) = (cover_image, cover_image_index) {
+        layout.add_image(cover_image_index, cover_image, 0.5);
+        layout.add_space(font_size);
+    }
+
+    // The title links back to the book on RoyalRoad.
+    let title_rects = layout.add_text(&book.title, Font::Bold, font_size * 2.0, Align::Center);
+    let page = layout.page();
+    for rect in title_rects {
+        page.links.push(Link {
+            rect,
+            target: LinkTarget::Uri(book.book_url.to_string()),
+        });
+    }
+
+    layout.add_text(
+        &format!("by: {}", book.author),
+        Font::Regular,
+        font_size * 1.3,
+        Align::Center,
+    );
+    layout.add_space(font_size);
+    layout.add_text(
+        &format!(
+            "Archived on: {}",
+            chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+        ),
+        Font::Regular,
+        font_size,
+        Align::Center,
+    );
+    for (label, value) in book.info() {
+        layout.add_text(
+            &format!("{label}: {value}"),
+            Font::Regular,
+            font_size * 0.9,
+            Align::Center,
+        );
+    }
+}
+
+/// Shorten a title to fit in the given width, adding an ellipsis if it had to be cut.
+fn truncate_to_width(title: &str, font: Font, font_size: f32, max_width: f32) -> String {
+    if font.text_width(title, font_size) <= max_width {
+        return title.to_string();
+    }
+
+    let max_width = max_width - font.text_width("…", font_size);
+    let mut truncated = String::new();
+    let mut width = 0.0;
+    for char in title.chars() {
+        width += font.char_width(char) as f32 * font_size / 1000.0;
+        if width > max_width {
+            break;
+        }
+        truncated.push(char);
+    }
+
+    format!("{}…", truncated.trim_end())
+}
+
+/// Lay out the table of contents, with each entry linking to its page.
+///
+/// Every entry is kept to one line so the number of pages it takes up is known before it's laid out.
+fn layout_toc(layout: &mut Layout, toc_entries: &[TocEntry], first_body_page: usize) {
+    let font_size = layout.font_size;
+    let line_height = font_size * LINE_HEIGHT;
+
+    layout.new_page();
+    layout.add_text("Contents", Font::Bold, font_size * 1.6, Align::Left);
+    layout.add_space(font_size);
+
+    for entry in toc_entries {
+        layout.ensure_space(line_height);
+
+        let font = if entry.level == 0 && toc_entries.iter().any(|entry| entry.level > 0) {
+            Font::Bold
+        } else {
+            Font::Regular
+        };
+        let page_number = (first_body_page + entry.page + 1).to_string();
+        let indent = entry.level as f32 * font_size * 1.5;
+        let number_width = Font::Regular.text_width(&page_number, font_size);
+        let title = truncate_to_width(
+            &entry.title,
+            font,
+            font_size,
+            layout.content_width() - indent - number_width - font_size * 2.0,
+        );
+
+        let top = layout.page_height - layout.y;
+        let baseline = top - (line_height + font_size * 0.7) / 2.0;
+        let margin = layout.margin;
+        let right_edge = layout.page_width - layout.margin;
+
+        let page = layout.page();
+        page.content
+            .begin_text()
+            .set_font(Name(font.resource_name()), font_size)
+            .set_text_matrix([1.0, 0.0, 0.0, 1.0, margin + indent, baseline])
+            .show(Str(&encode_text(&title)))
+            .set_font(Name(Font::Regular.resource_name()), font_size)
+            .set_text_matrix([1.0, 0.0, 0.0, 1.0, right_edge - number_width, baseline])
+            .show(Str(page_number.as_bytes()))
+            .end_text();
+        page.links.push(Link {
+            rect: Rect::new(margin, top - line_height, right_edge, top),
+            target: LinkTarget::Page(first_body_page + entry.page),
+        });
+
+        layout.y += line_height;
+    }
+}
+
+/// How many pages the table of contents will take up.
+fn toc_page_count(layout: &Layout, toc_entries: &[TocEntry]) -> usize {
+    let font_size = layout.font_size;
+    let heading_height = font_size * 1.6 * LINE_HEIGHT + font_size;
+    let lines_per_page = (layout.content_height() / (font_size * LINE_HEIGHT)).floor() as usize;
+    let first_page_lines =
+        ((layout.content_height() - heading_height) / (font_size * LINE_HEIGHT)).floor() as usize;
+
+    if toc_entries.len() <= first_page_lines {
+        1
+    } else {
+        1 + (toc_entries.len() - first_page_lines).div_ceil(lines_per_page.max(1))
+    }
+}
+
+/// Render a book as a pdf.
+///
+/// The images have to be downloaded beforehand, any image missing from the downloaded images gets a placeholder.
+pub fn render_pdf(
+    book: &Book,
+    pdf_args: &PdfArgs,
+    downloaded_images: &HashMap<Url, HttpResponse>,
+) -> Vec<u8> {
+    let (page_width, page_height) = page_dimensions(pdf_args.page_size);
+    let new_layout = || {
+        Layout::new(
+            page_width,
+            page_height,
+            pdf_args.margin * POINTS_PER_MM,
+            pdf_args.font_size,
+        )
+    };
+
+    let mut image_embedder = ImageEmbedder {
+        downloaded_images,
+        pdf_args,
+        images: Vec::new(),
+        url_indexes: HashMap::new(),
+        hash_indexes: HashMap::new(),
+    };
+
+    // Lay out the chapters first, so the table of contents knows what page each one starts on.
+    let mut body = new_layout();
+    let mut toc_entries: Vec<TocEntry> = Vec::new();
+
+    for (volume, chapters) in book.chapters_by_volume() {
+        let level = match volume {
+            Some(volume) => {
+                body.new_page();
+                body.y = body.margin + body.content_height() / 3.0;
+                body.add_text(
+                    &volume.title,
+                    Font::Bold,
+                    body.font_size * 2.0,
+                    Align::Center,
+                );
+                toc_entries.push(TocEntry {
+                    title: volume.title.clone(),
+                    level: 0,
+                    page: body.pages.len() - 1,
+                });
+
+                1
+            },
+            None => 0,
+        };
+
+        for (_, chapter) in chapters {
+            // Each chapter starts on a new page, which is the one after the current last page.
+            toc_entries.push(TocEntry {
+                title: chapter.heading(pdf_args.chapter_dates),
+                level,
+                page: body.pages.len(),
+            });
+            layout_chapter(&mut body, chapter, pdf_args, &mut image_embedder);
+        }
+    }
+
+    if pdf_args.author_notes == AuthorNotes::Appendix {
+        if let Some(appendix) = book.author_notes_appendix() {
+            body.new_page();
+            let appendix_page = body.pages.len() - 1;
+            body.add_text(
+                "Author's notes",
+                Font::Bold,
+                body.font_size * 1.6,
+                Align::Left,
+            );
+            body.add_space(body.font_size);
+            layout_html(&mut body, &appendix, &mut image_embedder);
+
+            toc_entries.push(TocEntry {
+                title: "Author's notes".to_string(),
+                level: 0,
+                page: appendix_page,
+            });
+        }
+    }
+
+    let cover_image_index = if pdf_args.no_images {
+        None
+    } else {
+        image_embedder.embed(&book.cover_image_url)
+    };
+    let mut cover = new_layout();
+    layout_cover(
+        &mut cover,
+        book,
+        cover_image_index.map(|index| &image_embedder.images[index]),
+        cover_image_index,
+    );
+
+    let first_body_page = cover.pages.len() + toc_page_count(&cover, &toc_entries);
+    let mut toc = new_layout();
+    layout_toc(&mut toc, &toc_entries, first_body_page);
+
+    // Put the pages together in order. The toc links already point past the cover & toc pages.
+    let mut document = new_layout();
+    document.pages.extend(cover.pages);
+    document.pages.extend(toc.pages);
+    document.pages.extend(body.pages);
+    document.add_page_numbers();
+
+    write_pdf(
+        book,
+        document,
+        &image_embedder.images,
+        &toc_entries,
+        first_body_page,
+    )
+}
+
+/// Write the laid out pages, images & outline into a pdf file.
+fn write_pdf(
+    book: &Book,
+    document: Layout,
+    images: &[EmbeddedImage],
+    toc_entries: &[TocEntry],
+    first_body_page: usize,
+) -> Vec<u8> {
+    let mut next_id = 1;
+    let mut new_ref = || {
+        let id = Ref::new(next_id);
+        next_id += 1;
+        id
+    };
+
+    let catalog_id = new_ref();
+    let page_tree_id = new_ref();
+    let info_id = new_ref();
+    let outline_id = new_ref();
+    let font_ids: Vec<Ref> = Font::ALL.iter().map(|_| new_ref()).collect();
+    let image_ids: Vec<(Ref, Option<Ref>)> = images
+        .iter()
+        .map(|image| (new_ref(), image.alpha.as_ref().map(|_| new_ref())))
+        .collect();
+    let page_ids: Vec<(Ref, Ref)> = document
+        .pages
+        .iter()
+        .map(|_| (new_ref(), new_ref()))
+        .collect();
+    let outline_item_ids: Vec<Ref> = toc_entries.iter().map(|_| new_ref()).collect();
+
+    let mut pdf = Pdf::new();
+
+    let mut catalog = pdf.catalog(catalog_id);
+    catalog.pages(page_tree_id);
+    if !toc_entries.is_empty() {
+        catalog
+            .outlines(outline_id)
+            .page_mode(PageMode::UseOutlines);
+    }
+    catalog.finish();
+
+    pdf.document_info(info_id)
+        .title(TextStr(&book.title))
+        .author(TextStr(&book.author))
+        .subject(TextStr(&html::html_to_text(&book.description)))
+        .creator(TextStr("royal_road_archiver"));
+
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().map(|(page_id, _)| *page_id))
+        .count(page_ids.len() as i32);
+
+    for (font, font_id) in Font::ALL.iter().zip(&font_ids) {
+        pdf.type1_font(*font_id)
+            .base_font(Name(font.base_font()))
+            .encoding_predefined(Name(b"WinAnsiEncoding"));
+    }
+
+    for (image, (image_id, alpha_id)) in images.iter().zip(&image_ids) {
+        let mut image_xobject = pdf.image_xobject(*image_id, &image.data);
+        image_xobject.filter(image.filter);
+        image_xobject
+            .width(image.width as i32)
+            .height(image.height as i32)
+            .bits_per_component(8);
+        if image.grayscale {
+            image_xobject.color_space().device_gray();
+        } else {
+            image_xobject.color_space().device_rgb();
+        }
+        if let Some(alpha_id) = alpha_id {
+            image_xobject.s_mask(*alpha_id);
+        }
+        image_xobject.finish();
+
+        if let (Some(alpha), Some(alpha_id)) = (&image.alpha, alpha_id) {
+            let mut alpha_xobject = pdf.image_xobject(*alpha_id, alpha);
+            alpha_xobject.filter(Filter::FlateDecode);
+            alpha_xobject
+                .width(image.width as i32)
+                .height(image.height as i32)
+                .bits_per_component(8);
+            alpha_xobject.color_space().device_gray();
+        }
+    }
+
+    let page_refs: Vec<Ref> = page_ids.iter().map(|(page_id, _)| *page_id).collect();
+    let page_height = document.page_height;
+
+    for (page, (page_id, content_id)) in document.pages.into_iter().zip(&page_ids) {
+        let mut pdf_page = pdf.page(*page_id);
+        pdf_page
+            .parent(page_tree_id)
+            .media_box(Rect::new(0.0, 0.0, document.page_width, page_height))
+            .contents(*content_id);
+
+        let mut resources = pdf_page.resources();
+        let mut fonts = resources.fonts();
+        for (font, font_id) in Font::ALL.iter().zip(&font_ids) {
+            fonts.pair(Name(font.resource_name()), *font_id);
+        }
+        fonts.finish();
+        let mut x_objects = resources.x_objects();
+        for image_index in &page.images {
+            x_objects.pair(
+                Name(format!("Im{image_index}").as_bytes()),
+                image_ids[*image_index].0,
+            );
+        }
+        x_objects.finish();
+        resources.finish();
+
+        let mut annotations = pdf_page.annotations();
+        for link in &page.links {
+            let mut annotation = annotations.push();
+            annotation
+                .subtype(AnnotationType::Link)
+                .rect(link.rect)
+                .border(0.0, 0.0, 0.0, None);
+
+            let mut action = annotation.action();
+            match &link.target {
+                LinkTarget::Page(target_page) => {
+                    action
+                        .action_type(ActionType::GoTo)
+                        .destination()
+                        .page(page_refs[*target_page])
+                        .xyz(0.0, page_height, None);
+                },
+                LinkTarget::Uri(uri) => {
+                    action.action_type(ActionType::Uri).uri(Str(uri.as_bytes()));
+                },
+            }
+        }
+        annotations.finish();
+        pdf_page.finish();
+
+        let content = compress_to_vec_zlib(&page.content.finish(), 6);
+        pdf.stream(*content_id, &content)
+            .filter(Filter::FlateDecode);
+    }
+
+    write_outline(
+        &mut pdf,
+        outline_id,
+        &outline_item_ids,
+        toc_entries,
+        &page_refs,
+        first_body_page,
+        page_height,
+    );
+
+    pdf.finish()
+}
+
+/// Write the pdf outline (bookmarks), with chapters nested under their volume.
+fn write_outline(
+    pdf: &mut Pdf,
+    outline_id: Ref,
+    outline_item_ids: &[Ref],
+    toc_entries: &[TocEntry],
+    page_refs: &[Ref],
+    first_body_page: usize,
+    page_height: f32,
+) {
+    if toc_entries.is_empty() {
+        return;
+    }
+
+    // The index of each entry's parent, if it's nested under a volume.
+    let parents: Vec<Option<usize>> = toc_entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            (entry.level > 0)
+                .then(|| (0..i).rev().find(|&j| toc_entries[j].level == 0))
+                .flatten()
+        })
+        .collect();
+
+    let siblings = |parent: Option<usize>| -> Vec<usize> {
+        (0..toc_entries.len())
+            .filter(|&i| parents[i] == parent)
+            .collect()
+    };
+
+    let top_level = siblings(None);
+    pdf.outline(outline_id)
+        .first(outline_item_ids[top_level[0]])
+        .last(outline_item_ids[*top_level.last().unwrap()])
+        .count(top_level.len() as i32);
+
+    for (i, entry) in toc_entries.iter().enumerate() {
+        let siblings = siblings(parents[i]);
+        let position = siblings.iter().position(|&j| j == i).unwrap();
+        let children = children_of(&parents, i);
+
+        let mut item = pdf.outline_item(outline_item_ids[i]);
+        item.title(TextStr(&entry.title))
+            .parent(parents[i].map_or(outline_id, |parent| outline_item_ids[parent]));
+        if position > 0 {
+            item.prev(outline_item_ids[siblings[position - 1]]);
+        }
+        if let Some(next) = siblings.get(position + 1) {
+            item.next(outline_item_ids[*next]);
+        }
+        if let (Some(first), Some(last)) = (children.first(), children.last()) {
+            item.first(outline_item_ids[*first])
+                .last(outline_item_ids[*last])
+                .count(-(children.len() as i32));
+        }
+        item.dest()
+            .page(page_refs[first_body_page + entry.page])
+            .xyz(0.0, page_height, None);
+    }
+}
+
+/// Get the entries nested under an entry.
+fn children_of(parents: &[Option<usize>], parent: usize) -> Vec<usize> {
+    (0..parents.len())
+        .filter(|&i| parents[i] == Some(parent))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the text of each paragraph block, with spaces & breaks as single spaces.
+    fn paragraph_texts(blocks: &[Block]) -> Vec<String> {
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Paragraph(pieces) => Some(
+                    pieces
+                        .iter()
+                        .map(|piece| match piece {
+                            Piece::Word(word, _) => word.as_str(),
+                            _ => " ",
+                        })
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn table_rows_stay_on_one_line() {
+        let html = html::string_to_html_fragment(
+            "<p>Stats</p><table><tr><th>Stat</th><th>Value</th></tr><tr><td>STR</td><td>10</td></tr></table>",
+        );
+
+        assert_eq!(
+            paragraph_texts(&html_to_blocks(&html)),
+            vec!["Stats", "Stat Value", "STR 10"]
+        );
+    }
+
+    #[test]
+    fn count_chars_the_fonts_cannot_show() {
+        assert_eq!(count_unsupported_chars("Plain “quoted” café – €5?"), 0);
+        assert_eq!(count_unsupported_chars("【HP】 Привет ┌─┐"), 11);
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{book::ChapterMetadata, GenerationError, Warning, WARNINGS};
+
+/// A record of a book as it was archived.
+///
+/// This gets stored next to the generated files so a later run can update the archive
+/// without downloading every chapter again.
+#[derive(Serialize, Deserialize)]
+pub struct BookRecord {
+    /// The RoyalRoad Url for the book.
+    pub book_url: Url,
+
+    /// The book's title.
+    pub title: String,
+
+    /// The recorded chapters in the order they appear in the book.
+    pub chapters: Vec<ChapterRecord>,
+}
+
+/// A record of a chapter as it was archived.
+///
+/// Only what's needed to tell whether the chapter has changed is recorded, the content itself is kept in the cache.
+#[derive(Serialize, Deserialize)]
+pub struct ChapterRecord {
+    /// The Url of the chapter.
+    pub chapter_url: Url,
+
+    /// The name of the chapter.
+    pub chapter_name: String,
+
+    /// When the chapter was downloaded.
+    pub fetched_at: DateTime<Local>,
+
+    /// The ``etag`` header the chapter page was served with.
+    #[serde(default)]
+    pub etag: Option<String>,
+
+    /// The ``last-modified`` header the chapter page was served with.
+    #[serde(default)]
+    pub last_modified: Option<String>,
+}
+
+impl ChapterRecord {
+    /// Whether the recorded chapter is still up to date with its metadata from the index,
+    /// meaning it has the same name & wasn't published or edited after it was downloaded.
+    ///
+    /// A chapter without a date can't be checked, so it never counts as fresh.
+    pub fn is_fresh(&self, metadata: &ChapterMetadata) -> bool {
+        self.chapter_url == metadata.url
+            && self.chapter_name == metadata.title
+            && metadata.date.is_some_and(|date| date <= self.fetched_at)
+    }
+
+    /// Whether the chapter was served with the same validators as when it was recorded,
+    /// meaning it hasn't changed since.
+    ///
+    /// A chapter served without any validators always counts as changed.
+    pub fn is_unchanged(&self, etag: &Option<String>, last_modified: &Option<String>) -> bool {
+        (self.etag.is_some() || self.last_modified.is_some())
+            && self.etag == *etag
+            && self.last_modified == *last_modified
+    }
+}
+
+impl BookRecord {
+    /// Get the directory of the cache holding the content of the recorded chapters in the given directory.
+    ///
+    /// Unlike the cache of a run, this one is kept once the run finishes, so the next update can read
+    /// the chapters that haven't changed from it instead of downloading them again.
+    pub fn cache_directory(directory: &Path) -> PathBuf {
+        directory.join(".royal_road_archiver").join("record_cache")
+    }
+
+    /// Get the path of the record file for a book in the given directory.
+    pub fn path(directory: &Path, file_name_title: &str) -> PathBuf {
+        directory.join(format!("{file_name_title}.record.json"))
+    }
+
+    /// Read a record from the given path.
+    ///
+    /// Returns None if there is no record. If the record can't be read a warning is added & None is returned.
+    pub fn read(record_path: &Path) -> Option<BookRecord> {
+        if !record_path.exists() {
+            return None;
+        }
+
+        let result = File::open(record_path)
+            .map_err(|error| error.to_string())
+            .and_then(|file| {
+                serde_json::from_reader(BufReader::new(file)).map_err(|error| error.to_string())
+            });
+
+        match result {
+            Ok(record) => Some(record),
+            Err(error) => {
+                let warning = Warning::RecordReadError {
+                    warning_msg: "Unable to read the record of the previous run, every chapter will be downloaded again".to_string(),
+                    record_path: record_path.to_path_buf(),
+                    error,
+                };
+                WARNINGS.lock().unwrap().add_warning(warning);
+
+                None
+            },
+        }
+    }
+
+    /// Write the record to the given path, replacing any record that is already there.
+    pub fn write(&self, record_path: &Path) -> Result<(), GenerationError> {
+        let file = match File::create(record_path) {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(GenerationError::FileCreationError {
+                    error,
+                    file_path: record_path.to_path_buf(),
+                })
+            },
+        };
+
+        match serde_json::to_writer(BufWriter::new(file), self) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(GenerationError::RecordWriteError {
+                error,
+                file_path: record_path.to_path_buf(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chapter_record_freshness() {
+        let url: Url = "https://www.royalroad.com/fiction/1/test/chapter/11/one"
+            .parse()
+            .unwrap();
+        let chapter_record = ChapterRecord {
+            chapter_url: url.clone(),
+            chapter_name: "One".to_string(),
+            fetched_at: "2024-01-01T00:00:00Z".parse().unwrap(),
+            etag: None,
+            last_modified: None,
+        };
+        let metadata = |title: &str, date: Option<&str>| ChapterMetadata {
+            id: 11,
+            title: title.to_string(),
+            url: url.clone(),
+            date: date.map(|date| date.parse().unwrap()),
+            order: Some(0),
+            volume_id: None,
+        };
+
+        assert!(chapter_record.is_fresh(&metadata("One", Some("2023-06-01T00:00:00Z"))));
+        // Renamed.
+        assert!(!chapter_record.is_fresh(&metadata("One (edited)", Some("2023-06-01T00:00:00Z"))));
+        // Published again after it was downloaded.
+        assert!(!chapter_record.is_fresh(&metadata("One", Some("2024-06-01T00:00:00Z"))));
+        // No date to check.
+        assert!(!chapter_record.is_fresh(&metadata("One", None)));
+    }
+}
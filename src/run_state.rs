@@ -0,0 +1,244 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::MutexGuard,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{cache::Cache, GenerationError, GenerationWarnings, Warning, WARNINGS};
+
+/// The state of an archival run, kept on disk so an interrupted run can be resumed.
+///
+/// The state lives in ``<output directory>/.royal_road_archiver/<hash of the book url>``.
+/// Downloaded chapters & images are kept in its cache, so resuming only downloads what's missing.
+/// The chapters & images that failed to download are recorded in ``failures.json``, so a resumed run can report them.
+pub struct RunState {
+    /// The directory holding the default cache & the failures of the run.
+    directory: PathBuf,
+}
+
+/// The chapters & images a run failed to download.
+///
+/// They never make it into the cache, so resuming the run retries just these.
+#[derive(Serialize, Deserialize, Default)]
+struct RunFailures {
+    /// The urls of the chapters that failed to download.
+    failed_chapters: Vec<Url>,
+
+    /// The urls of the images that failed to download.
+    failed_images: Vec<Url>,
+}
+
+impl RunState {
+    /// Set up the run state for a book in the given output directory.
+    ///
+    /// Unless resuming, any state left by an earlier run is thrown away.
+    pub fn new(
+        book_url: &Url,
+        output_directory: &Path,
+        resume: bool,
+    ) -> Result<RunState, GenerationError> {
+        let key = format!("{:x}", Sha256::digest(book_url.as_str().as_bytes()));
+        let directory = output_directory
+            .join(".royal_road_archiver")
+            .join(&key[..16]);
+
+        if !resume && directory.exists() {
+            if let Err(error) = fs::remove_dir_all(&directory) {
+                return Err(GenerationError::DirectoryDeletionError {
+                    error,
+                    directory_path: directory,
+                });
+            }
+        }
+
+        if resume && directory.exists() {
+            match read_failures(&failures_path(&directory)) {
+                Some(failures) => println!(
+                    "\nResuming the previous run, retrying the {0} chapters & {1} images it failed to download.",
+                    failures.failed_chapters.len(),
+                    failures.failed_images.len()
+                ),
+                None => {
+                    println!("\nResuming the previous run, only downloading what it didn't finish.")
+                },
+            }
+        }
+
+        if let Err(error) = fs::create_dir_all(&directory) {
+            return Err(GenerationError::DirectoryCreationError {
+                error,
+                directory_path: directory,
+            });
+        }
+
+        Ok(RunState { directory })
+    }
+
+    /// Open the cache that stores the downloads of this run.
+    ///
+    /// Used when no cache directory has been given, so the downloads still survive an interrupted run.
+    pub fn default_cache(&self) -> Result<Cache, GenerationError> {
+        Cache::new(self.directory.join("cache"))
+    }
+
+    /// Finish the run with the result it ended with, passing the result on.
+    ///
+    /// A run that failed, or couldn't download some of its images, has what failed recorded & its state kept,
+    /// so it can be resumed. Otherwise the run state is deleted, since it's good to clean up after yourself.
+    pub fn finish(
+        self,
+        result: Result<MutexGuard<'static, GenerationWarnings>, GenerationError>,
+    ) -> Result<MutexGuard<'static, GenerationWarnings>, GenerationError> {
+        match result {
+            Ok(mut warnings) => {
+                let failures = RunFailures {
+                    failed_chapters: Vec::new(),
+                    failed_images: failed_images(&warnings),
+                };
+
+                if failures.failed_images.is_empty() {
+                    self.delete(&mut warnings);
+                } else if self.write_failures(&failures, &mut warnings) {
+                    let warning = Warning::IncompleteRun {
+                        warning_msg: format!(
+                            "{0} images couldn't be downloaded. Run again with --resume to retry just them",
+                            failures.failed_images.len()
+                        ),
+                    };
+                    warnings.add_warning(warning);
+                }
+
+                Ok(warnings)
+            },
+            Err(error) => {
+                let failed_chapters = match &error {
+                    GenerationError::IncompleteDownloadError { failed_chapters } => {
+                        failed_chapters.clone()
+                    },
+                    _ => Vec::new(),
+                };
+
+                let mut warnings = WARNINGS.lock().unwrap();
+                let failures = RunFailures {
+                    failed_chapters,
+                    failed_images: failed_images(&warnings),
+                };
+                self.write_failures(&failures, &mut warnings);
+
+                Err(error)
+            },
+        }
+    }
+
+    /// Delete the run state.
+    fn delete(self, warnings: &mut GenerationWarnings) {
+        if let Err(error) = fs::remove_dir_all(&self.directory) {
+            let warning = Warning::TempDirDeletionError {
+                warning_msg: "Unable to delete the state of the finished run".to_string(),
+                temp_directory_path: self.directory,
+                error,
+            };
+            warnings.add_warning(warning);
+            return;
+        }
+
+        // Remove the parent directory too if no other runs are using it.
+        if let Some(parent) = self.directory.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+
+    /// Write the failures of the run into its state. Adds a warning & returns false if they can't be written.
+    fn write_failures(&self, failures: &RunFailures, warnings: &mut GenerationWarnings) -> bool {
+        let failures_path = failures_path(&self.directory);
+
+        let result = File::create(&failures_path)
+            .map_err(|error| error.to_string())
+            .and_then(|file| {
+                serde_json::to_writer(BufWriter::new(file), failures)
+                    .map_err(|error| error.to_string())
+            });
+
+        match result {
+            Ok(_) => true,
+            Err(error) => {
+                let warning = Warning::RunStateWriteError {
+                    warning_msg:
+                        "Unable to record what failed to download, resuming will still retry it"
+                            .to_string(),
+                    state_path: failures_path,
+                    error,
+                };
+                warnings.add_warning(warning);
+
+                false
+            },
+        }
+    }
+}
+
+/// Get the path of the failures file in a run state directory.
+fn failures_path(directory: &Path) -> PathBuf {
+    directory.join("failures.json")
+}
+
+/// Read the failures recorded by an earlier run. Returns None if there are none or they can't be read.
+fn read_failures(failures_path: &Path) -> Option<RunFailures> {
+    let file = File::open(failures_path).ok()?;
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+/// Get the urls of the images that failed to download from the warnings of a run.
+fn failed_images(warnings: &GenerationWarnings) -> Vec<Url> {
+    let mut failed_images: Vec<Url> = Vec::new();
+
+    for warning in warnings.get_warnings() {
+        if let Warning::ImageDownloadError { url, .. } = warning {
+            if !failed_images.contains(url) {
+                failed_images.push(url.clone());
+            }
+        }
+    }
+
+    failed_images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_chapters_are_recorded_for_resuming() {
+        let output_directory = std::env::temp_dir().join(format!(
+            "royal_road_archiver_run_state_{}",
+            std::process::id()
+        ));
+        let book_url: Url = "https://www.royalroad.com/fiction/1/test".parse().unwrap();
+        let chapter_url: Url = "https://www.royalroad.com/fiction/1/test/chapter/12/two"
+            .parse()
+            .unwrap();
+
+        let run_state = RunState::new(&book_url, &output_directory, false).unwrap();
+        let failures_path = failures_path(&run_state.directory);
+        let result = run_state.finish(Err(GenerationError::IncompleteDownloadError {
+            failed_chapters: vec![chapter_url.clone()],
+        }));
+        assert!(result.is_err());
+
+        let failures = read_failures(&failures_path).unwrap();
+        assert_eq!(failures.failed_chapters, vec![chapter_url]);
+
+        // Resuming keeps the failures, starting over throws them away.
+        RunState::new(&book_url, &output_directory, true).unwrap();
+        assert!(read_failures(&failures_path).is_some());
+        RunState::new(&book_url, &output_directory, false).unwrap();
+        assert!(read_failures(&failures_path).is_none());
+
+        fs::remove_dir_all(&output_directory).unwrap();
+    }
+}
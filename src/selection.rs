@@ -0,0 +1,267 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::Args;
+use regex::Regex;
+
+use crate::book::ChapterMetadata;
+
+/// struct that corresponds to arguments for choosing which chapters get archived.
+///
+/// All the given options have to match for a chapter to be archived.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ChapterSelection {
+    /// Only archive the chapters in this range. Chapters are numbered from 1.
+    /// Works like rust ranges: 10..50 is chapters 10 to 49, 10..=50 is chapters 10 to 50, 10.. & ..50 leave one end open.
+    #[arg(long, global = true, value_parser = parse_chapter_range)]
+    pub chapters: Option<ChapterRange>,
+
+    /// Only archive chapters published after this date. E.G. 2023-06-01 or 2023-06-01T18:00:00Z
+    #[arg(long, global = true, value_parser = parse_date)]
+    pub published_after: Option<DateTime<Utc>>,
+
+    /// Only archive chapters with a title matching this regex.
+    #[arg(long, global = true, value_parser = parse_regex)]
+    pub title_regex: Option<Regex>,
+
+    /// Only archive the first N of the selected chapters.
+    #[arg(long, global = true)]
+    pub first: Option<usize>,
+
+    /// Only archive the last N of the selected chapters.
+    #[arg(long, global = true)]
+    pub last: Option<usize>,
+}
+
+/// A range of chapter numbers. Chapters are numbered from 1.
+#[derive(Debug, Clone, Copy)]
+pub struct ChapterRange {
+    /// The first chapter in the range.
+    pub start: usize,
+
+    /// The last chapter in the range (inclusive). None if the range is open ended.
+    pub end: Option<usize>,
+}
+
+impl ChapterRange {
+    /// Whether the given chapter number is in the range.
+    pub fn contains(&self, chapter_number: usize) -> bool {
+        chapter_number >= self.start && self.end.is_none_or(|end| chapter_number <= end)
+    }
+}
+
+impl ChapterSelection {
+    /// Whether no options have been given, meaning every chapter is selected.
+    pub fn is_empty(&self) -> bool {
+        self.chapters.is_none()
+            && self.published_after.is_none()
+            && self.title_regex.is_none()
+            && self.first.is_none()
+            && self.last.is_none()
+    }
+
+    /// Filter the chapters of a book down to the selected chapters, keeping their order.
+    pub fn apply(&self, chapters: Vec<ChapterMetadata>) -> Vec<ChapterMetadata> {
+        let mut selected_chapters: Vec<ChapterMetadata> = chapters
+            .into_iter()
+            .enumerate()
+            .filter(|(i, chapter)| {
+                let in_range = self.chapters.is_none_or(|range| range.contains(i + 1));
+
+                // Chapters without a known publish date can't be shown to be new enough.
+                let published_after = match self.published_after {
+                    Some(published_after) => {
+                        chapter.date.is_some_and(|date| date > published_after)
+                    },
+                    None => true,
+                };
+
+                let title_matches = self
+                    .title_regex
+                    .as_ref()
+                    .is_none_or(|regex| regex.is_match(&chapter.title));
+
+                in_range && published_after && title_matches
+            })
+            .map(|(_, chapter)| chapter)
+            .collect();
+
+        if let Some(first) = self.first {
+            selected_chapters.truncate(first);
+        }
+
+        if let Some(last) = self.last {
+            let skip = selected_chapters.len().saturating_sub(last);
+            selected_chapters.drain(..skip);
+        }
+
+        selected_chapters
+    }
+}
+
+/// Parse a chapter range written like a rust range. E.G. 10..50, 10..=50, 10.. or ..50
+fn parse_chapter_range(string: &str) -> Result<ChapterRange, String> {
+    let parse_number = |number: &str| {
+        number
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| format!("{number} is not a valid chapter number"))
+    };
+
+    let (start, end, inclusive) = if let Some((start, end)) = string.split_once("..=") {
+        (start, end, true)
+    } else if let Some((start, end)) = string.split_once("..") {
+        (start, end, false)
+    } else {
+        // A single chapter number.
+        let chapter_number = parse_number(string)?;
+        return Ok(ChapterRange {
+            start: chapter_number,
+            end: Some(chapter_number),
+        });
+    };
+
+    let start = match start.trim() {
+        "" => 1,
+        start => parse_number(start)?,
+    };
+
+    let end = match end.trim() {
+        "" if inclusive => return Err("an inclusive range needs an end".to_string()),
+        "" => None,
+        end if inclusive => Some(parse_number(end)?),
+        end => Some(parse_number(end)?.saturating_sub(1)),
+    };
+
+    if end.is_some_and(|end| end < start) {
+        return Err(format!("the range {string} does not contain any chapters"));
+    }
+
+    Ok(ChapterRange { start, end })
+}
+
+/// Parse a date given either as YYYY-MM-DD or an RFC 3339 timestamp.
+fn parse_date(string: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(string) {
+        return Ok(date_time.with_timezone(&Utc));
+    }
+
+    match NaiveDate::parse_from_str(string, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc()),
+        Err(_) => Err(format!(
+            "{string} is not a valid date, use YYYY-MM-DD or an RFC 3339 timestamp"
+        )),
+    }
+}
+
+/// Parse a regex.
+fn parse_regex(string: &str) -> Result<Regex, String> {
+    Regex::new(string).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make a chapter with the given title & publish date.
+    fn chapter(id: u64, title: &str, date: Option<&str>) -> ChapterMetadata {
+        ChapterMetadata {
+            id,
+            title: title.to_string(),
+            url: format!("https://www.royalroad.com/fiction/1/test/chapter/{id}/chapter")
+                .parse()
+                .unwrap(),
+            date: date.map(|date| parse_date(date).unwrap()),
+            order: Some(id),
+            volume_id: None,
+        }
+    }
+
+    fn chapters() -> Vec<ChapterMetadata> {
+        vec![
+            chapter(1, "Prologue", Some("2023-01-01")),
+            chapter(2, "Chapter 1", Some("2023-02-01")),
+            chapter(3, "Interlude", None),
+            chapter(4, "Chapter 2", Some("2023-04-01")),
+            chapter(5, "Chapter 3", Some("2023-05-01")),
+        ]
+    }
+
+    fn ids(chapters: &[ChapterMetadata]) -> Vec<u64> {
+        chapters.iter().map(|chapter| chapter.id).collect()
+    }
+
+    #[test]
+    fn parse_chapter_range_forms() {
+        let range = parse_chapter_range("10..50").unwrap();
+        assert_eq!((range.start, range.end), (10, Some(49)));
+
+        let range = parse_chapter_range("10..=50").unwrap();
+        assert_eq!((range.start, range.end), (10, Some(50)));
+
+        let range = parse_chapter_range("10..").unwrap();
+        assert_eq!((range.start, range.end), (10, None));
+
+        let range = parse_chapter_range("..50").unwrap();
+        assert_eq!((range.start, range.end), (1, Some(49)));
+
+        let range = parse_chapter_range(" 7 ").unwrap();
+        assert_eq!((range.start, range.end), (7, Some(7)));
+    }
+
+    #[test]
+    fn parse_chapter_range_errors() {
+        assert!(parse_chapter_range("10..=").is_err());
+        assert!(parse_chapter_range("50..10").is_err());
+        assert!(parse_chapter_range("10..10").is_err());
+        assert!(parse_chapter_range("a..5").is_err());
+        assert!(parse_chapter_range("").is_err());
+    }
+
+    #[test]
+    fn empty_selection_keeps_everything() {
+        let selection = ChapterSelection::default();
+        assert!(selection.is_empty());
+        assert_eq!(ids(&selection.apply(chapters())), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn apply_range() {
+        let selection = ChapterSelection {
+            chapters: Some(parse_chapter_range("2..=4").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&selection.apply(chapters())), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn apply_published_after_skips_undated_chapters() {
+        let selection = ChapterSelection {
+            published_after: Some(parse_date("2023-01-15").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&selection.apply(chapters())), vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn apply_title_regex_then_first_and_last() {
+        let selection = ChapterSelection {
+            title_regex: Some(parse_regex("^Chapter").unwrap()),
+            first: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(ids(&selection.apply(chapters())), vec![2, 4]);
+
+        let selection = ChapterSelection {
+            title_regex: Some(parse_regex("^Chapter").unwrap()),
+            last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(ids(&selection.apply(chapters())), vec![4, 5]);
+
+        // Asking for more chapters than there are keeps all of them.
+        let selection = ChapterSelection {
+            last: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(selection.apply(chapters()).len(), 5);
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::GenerationError;
+
+/// A text-to-speech backend that turns text into an audio file.
+///
+/// Implement this to plug a different speech engine into audiobook generation.
+pub trait TtsBackend {
+    /// The name of the backend. Used for error messages.
+    fn name(&self) -> &str;
+
+    /// The file extension of the audio files this backend produces.
+    fn file_extension(&self) -> &str;
+
+    /// Speak the given text and write the resulting audio to the output path.
+    fn synthesize(&self, text: &str, output_path: &Path) -> Result<(), GenerationError>;
+}
+
+/// A backend that uses a locally installed copy of espeak-ng.
+pub struct EspeakNg {
+    /// The espeak-ng voice to use, e.g. ``en-us``. Uses the espeak-ng default if None.
+    pub voice: Option<String>,
+
+    /// The speaking speed in words per minute. Uses the espeak-ng default if None.
+    pub words_per_minute: Option<u32>,
+}
+
+impl TtsBackend for EspeakNg {
+    fn name(&self) -> &str {
+        "espeak-ng"
+    }
+
+    fn file_extension(&self) -> &str {
+        "wav"
+    }
+
+    fn synthesize(&self, text: &str, output_path: &Path) -> Result<(), GenerationError> {
+        run_tts_command(self.name(), self.command(output_path), text)
+    }
+}
+
+impl EspeakNg {
+    /// Build the espeak-ng command that reads text from stdin & writes the audio to the output path.
+    fn command(&self, output_path: &Path) -> Command {
+        let mut command = Command::new("espeak-ng");
+        command.arg("--stdin").arg("-w").arg(output_path);
+
+        if let Some(voice) = &self.voice {
+            command.arg("-v").arg(voice);
+        }
+        if let Some(words_per_minute) = self.words_per_minute {
+            command.arg("-s").arg(words_per_minute.to_string());
+        }
+
+        command
+    }
+}
+
+/// A backend that uses a locally installed copy of piper.
+pub struct Piper {
+    /// The path to the piper voice model (.onnx) to use.
+    pub model: PathBuf,
+}
+
+impl TtsBackend for Piper {
+    fn name(&self) -> &str {
+        "piper"
+    }
+
+    fn file_extension(&self) -> &str {
+        "wav"
+    }
+
+    fn synthesize(&self, text: &str, output_path: &Path) -> Result<(), GenerationError> {
+        run_tts_command(self.name(), self.command(output_path), text)
+    }
+}
+
+impl Piper {
+    /// Build the piper command that reads text from stdin & writes the audio to the output path.
+    fn command(&self, output_path: &Path) -> Command {
+        let mut command = Command::new("piper");
+        command
+            .arg("--model")
+            .arg(&self.model)
+            .arg("--output_file")
+            .arg(output_path);
+
+        command
+    }
+}
+
+/// Start a tts engine, write the text to its stdin & wait for it to finish.
+fn run_tts_command(engine: &str, mut command: Command, text: &str) -> Result<(), GenerationError> {
+    let mut child = match command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(error) => {
+            return Err(GenerationError::TtsStartError {
+                engine: engine.to_string(),
+                error,
+            })
+        },
+    };
+
+    // The stdin handle gets dropped at the end of this block so the engine knows the text is finished.
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        if let Err(error) = stdin.write_all(text.as_bytes()) {
+            return Err(GenerationError::TtsStartError {
+                engine: engine.to_string(),
+                error,
+            });
+        }
+    }
+
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(GenerationError::TtsSynthesisError {
+            engine: engine.to_string(),
+            status: status.to_string(),
+        }),
+        Err(error) => Err(GenerationError::TtsStartError {
+            engine: engine.to_string(),
+            error,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    fn args(command: &Command) -> Vec<&OsStr> {
+        command.get_args().collect()
+    }
+
+    #[test]
+    fn espeak_ng_default_arguments() {
+        let espeak_ng = EspeakNg {
+            voice: None,
+            words_per_minute: None,
+        };
+        let command = espeak_ng.command(Path::new("out/chapter.wav"));
+
+        assert_eq!(command.get_program(), "espeak-ng");
+        assert_eq!(args(&command), ["--stdin", "-w", "out/chapter.wav"]);
+    }
+
+    #[test]
+    fn espeak_ng_voice_and_speed_arguments() {
+        let espeak_ng = EspeakNg {
+            voice: Some("en-gb".to_string()),
+            words_per_minute: Some(200),
+        };
+        let command = espeak_ng.command(Path::new("chapter.wav"));
+
+        assert_eq!(
+            args(&command),
+            ["--stdin", "-w", "chapter.wav", "-v", "en-gb", "-s", "200"]
+        );
+    }
+
+    #[test]
+    fn piper_arguments() {
+        let piper = Piper {
+            model: PathBuf::from("voices/en_US-amy-medium.onnx"),
+        };
+        let command = piper.command(Path::new("chapter.wav"));
+
+        assert_eq!(command.get_program(), "piper");
+        assert_eq!(
+            args(&command),
+            [
+                "--model",
+                "voices/en_US-amy-medium.onnx",
+                "--output_file",
+                "chapter.wav"
+            ]
+        );
+        assert_eq!(piper.file_extension(), "wav");
+    }
+
+    #[test]
+    fn run_tts_command_missing_engine() {
+        let command = Command::new("royal-road-archiver-no-such-tts-engine");
+
+        assert!(matches!(
+            run_tts_command("missing", command, "Hello"),
+            Err(GenerationError::TtsStartError { engine, .. }) if engine == "missing"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_tts_command_exit_status() {
+        // Stand in for an engine by reading the text from stdin & exiting with the given status.
+        let engine = |status: u8| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("cat > /dev/null; exit {status}"));
+            command
+        };
+
+        assert!(run_tts_command("sh", engine(0), "Hello").is_ok());
+        assert!(matches!(
+            run_tts_command("sh", engine(3), "Hello"),
+            Err(GenerationError::TtsSynthesisError { engine, .. }) if engine == "sh"
+        ));
+    }
+}
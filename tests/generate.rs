@@ -0,0 +1,259 @@
+//! End to end tests, generating books from the small fixture book in ``tests/fixtures/book``.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Read,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use royal_road_archiver_lib::{
+    generate_epub_with_fetcher, generate_markdown_with_fetcher, AuthorNotes, DownloadArgs,
+    EpubArgs, Fetcher, FixtureFetcher, GenerationError, HttpResponse, ImageArgs, MarkdownArgs,
+};
+use url::Url;
+
+fn fixture_fetcher() -> FixtureFetcher {
+    FixtureFetcher {
+        directory: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/book"),
+    }
+}
+
+/// A fetcher that caches the fixtures it serves, like the reqwest fetcher does, keeping track of what it downloads.
+struct CachingFetcher {
+    /// The fixture directories to download from, the first one that has the url wins.
+    fixtures: Vec<FixtureFetcher>,
+
+    cache: Mutex<HashMap<Url, HttpResponse>>,
+
+    /// The urls that weren't served from the cache.
+    downloads: Mutex<Vec<Url>>,
+}
+
+impl CachingFetcher {
+    fn new(fixture_names: &[&str], cache: HashMap<Url, HttpResponse>) -> Self {
+        let fixtures = fixture_names
+            .iter()
+            .map(|fixture_name| FixtureFetcher {
+                directory: PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests/fixtures")
+                    .join(fixture_name),
+            })
+            .collect();
+
+        CachingFetcher {
+            fixtures,
+            cache: Mutex::new(cache),
+            downloads: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn download(&self, url: &Url) -> Result<HttpResponse, GenerationError> {
+        self.downloads.lock().unwrap().push(url.clone());
+
+        let mut result = Err(GenerationError::FixtureNotFoundError { url: url.clone() });
+        for fixture in &self.fixtures {
+            result = fixture.fetch(url);
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        let response = result?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(url.clone(), response.clone());
+        Ok(response)
+    }
+}
+
+impl Fetcher for CachingFetcher {
+    fn fetch(&self, url: &Url) -> Result<HttpResponse, GenerationError> {
+        let cached_response = self.cache.lock().unwrap().get(url).cloned();
+        match cached_response {
+            Some(response) => Ok(response),
+            None => self.download(url),
+        }
+    }
+
+    fn fetch_fresh(&self, url: &Url) -> Result<HttpResponse, GenerationError> {
+        self.download(url)
+    }
+}
+
+fn book_url() -> Url {
+    Url::parse("https://www.royalroad.com/fiction/1/test").unwrap()
+}
+
+/// Get an empty output directory for a test.
+fn output_directory(test_name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!(
+        "royal_road_archiver_{test_name}_{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn read_zip_file(archive: &mut zip::ZipArchive<File>, name: &str) -> String {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    content
+}
+
+#[test]
+fn generate_epub_from_fixtures() {
+    let output_directory = output_directory("epub");
+    let epub_args = EpubArgs {
+        no_images: false,
+        update: false,
+        chapter_dates: false,
+        author_notes: AuthorNotes::Inline,
+        image_args: ImageArgs::default(),
+    };
+
+    let warnings = generate_epub_with_fetcher(
+        epub_args,
+        DownloadArgs::default(),
+        &fixture_fetcher(),
+        book_url(),
+        output_directory.clone(),
+    )
+    .unwrap();
+    drop(warnings);
+
+    let mut archive =
+        zip::ZipArchive::new(File::open(output_directory.join("Test & Book.epub")).unwrap())
+            .unwrap();
+
+    // The cover url has a query, so this only works if the fixture fetcher keeps it.
+    assert!(archive.by_name("OEBPS/cover.png").is_ok());
+
+    let chapter_one = read_zip_file(&mut archive, "OEBPS/chapter_1.xhtml");
+    assert!(chapter_one.contains(r#"<div class="author-note"><p>Before one.</p></div>"#));
+    assert!(chapter_one.contains("<p>Hello &amp; welcome.</p>"));
+    assert!(chapter_one.contains(r#"alt="green"/>"#));
+    assert!(!chapter_one.contains("stolen"));
+
+    let chapter_two = read_zip_file(&mut archive, "OEBPS/chapter_2.xhtml");
+    assert!(chapter_two.contains("<p>The <em>second</em> chapter.</p>"));
+    assert!(chapter_two.contains(r#"<div class="author-note"><p>After two.</p></div>"#));
+    assert!(!chapter_two.contains("stolen"));
+
+    // Every image the chapters link to is in the epub & its manifest.
+    let content = read_zip_file(&mut archive, "OEBPS/content.opf");
+    for chapter in [&chapter_one, &chapter_two] {
+        let image_file = chapter
+            .split(r#"<img src=""#)
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+
+        assert!(archive.by_name(&format!("OEBPS/{image_file}")).is_ok());
+        assert!(content.contains(&format!(
+            r#"<item media-type="image/png" id="{image_file}" href="{image_file}"/>"#
+        )));
+    }
+
+    fs::remove_dir_all(&output_directory).unwrap();
+}
+
+#[test]
+fn generate_markdown_from_fixtures() {
+    let output_directory = output_directory("markdown");
+    let markdown_args = MarkdownArgs {
+        no_chapter_titles: false,
+        no_image_tags: false,
+        split_chapters: false,
+        update: false,
+        chapter_dates: false,
+        author_notes: AuthorNotes::Inline,
+    };
+
+    let warnings = generate_markdown_with_fetcher(
+        markdown_args,
+        DownloadArgs::default(),
+        &fixture_fetcher(),
+        book_url(),
+        output_directory.clone(),
+    )
+    .unwrap();
+    drop(warnings);
+
+    let markdown = fs::read_to_string(output_directory.join("Test & Book.md")).unwrap();
+    assert!(markdown.starts_with("Test & Book\n\nby: Auth\n"));
+    assert!(markdown.contains("----\nOne\n\nBefore one.\n\nHello & welcome.\n\n![green](assets/"));
+    assert!(markdown.contains("----\nTwo\n\nThe *second* chapter.\n\n![blue](assets/"));
+    assert!(markdown.contains("After two."));
+    assert!(!markdown.contains("stolen"));
+
+    // The images are linked with paths relative to the markdown file.
+    for line in markdown.lines().filter(|line| line.starts_with("![")) {
+        let image_path = line
+            .split("](")
+            .nth(1)
+            .and_then(|rest| rest.strip_suffix(')'))
+            .unwrap();
+        assert!(output_directory.join(image_path).is_file());
+    }
+
+    fs::remove_dir_all(&output_directory).unwrap();
+}
+
+#[test]
+fn update_only_downloads_new_chapters() {
+    let output_directory = output_directory("update");
+    let markdown_args = |update: bool| MarkdownArgs {
+        no_chapter_titles: false,
+        no_image_tags: false,
+        split_chapters: false,
+        update,
+        chapter_dates: false,
+        author_notes: AuthorNotes::Inline,
+    };
+
+    let fetcher = CachingFetcher::new(&["book"], HashMap::new());
+    let warnings = generate_markdown_with_fetcher(
+        markdown_args(false),
+        DownloadArgs::default(),
+        &fetcher,
+        book_url(),
+        output_directory.clone(),
+    )
+    .unwrap();
+    drop(warnings);
+
+    // The book got a new chapter since, RoyalRoad doesn't send any validators to tell the old ones are unchanged.
+    let fetcher = CachingFetcher::new(
+        &["book_with_new_chapter", "book"],
+        fetcher.cache.into_inner().unwrap(),
+    );
+    let warnings = generate_markdown_with_fetcher(
+        markdown_args(true),
+        DownloadArgs::default(),
+        &fetcher,
+        book_url(),
+        output_directory.clone(),
+    )
+    .unwrap();
+    drop(warnings);
+
+    let chapter_url = book_url().join("test/chapter/13/three").unwrap();
+    assert_eq!(
+        fetcher.downloads.into_inner().unwrap(),
+        vec![book_url(), chapter_url]
+    );
+
+    let markdown = fs::read_to_string(output_directory.join("Test & Book.md")).unwrap();
+    assert!(markdown.contains("----\nTwo\n\nThe *second* chapter."));
+    assert!(markdown.contains("----\nThree\n\nThe third chapter."));
+
+    fs::remove_dir_all(&output_directory).unwrap();
+}